@@ -0,0 +1,153 @@
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::{connect_with, Connection};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::{
+    executor::{block_on, ThreadPool},
+    future::{join, join_all, poll_fn, RemoteHandle},
+    task::SpawnExt,
+};
+use protocol::{CloneContext, Read, Write};
+use std::pin::Pin;
+
+const MESSAGES: u64 = 64;
+const BATCH: u64 = 1024;
+
+async fn send<T, C: Write<T> + Unpin>(ctx: &mut C, item: T) {
+    poll_fn(|cx| Pin::new(&mut *ctx).poll_ready(cx))
+        .await
+        .ok()
+        .unwrap();
+    Pin::new(&mut *ctx).write(item).ok().unwrap();
+}
+
+async fn flush<T, C: Write<T> + Unpin>(ctx: &mut C) {
+    poll_fn(|cx| Pin::new(&mut *ctx).poll_flush(cx))
+        .await
+        .ok()
+        .unwrap();
+}
+
+async fn receive<T, C: Read<T> + Unpin>(ctx: &mut C) -> T {
+    poll_fn(|cx| Pin::new(&mut *ctx).read(cx))
+        .await
+        .ok()
+        .unwrap()
+}
+
+/// Opens `contexts` contexts from `a` and streams `MESSAGES` items through
+/// each of them concurrently.
+async fn open(pool: &ThreadPool, a: &mut Connection, contexts: u64) -> Vec<RemoteHandle<()>> {
+    let mut tasks = vec![];
+
+    for _ in 0..contexts {
+        let mut fork = a.fork_owned();
+        let (mut ctx, id) = poll_fn(|cx| protocol::Future::poll(Pin::new(&mut fork), cx, &mut *a))
+            .await
+            .ok()
+            .unwrap();
+
+        send(a, id).await;
+        flush::<u64, _>(a).await;
+
+        tasks.push(
+            pool.spawn_with_handle(async move {
+                for item in 0..MESSAGES {
+                    send(&mut ctx, item).await;
+                }
+                flush::<u64, _>(&mut ctx).await;
+            })
+            .unwrap(),
+        );
+    }
+
+    tasks
+}
+
+/// Joins `contexts` contexts opened by the peer and reads `MESSAGES` items
+/// from each of them concurrently.
+async fn accept(pool: &ThreadPool, b: &mut Connection, contexts: u64) -> Vec<RemoteHandle<()>> {
+    let mut tasks = vec![];
+
+    for _ in 0..contexts {
+        let id = receive::<u64, _>(b).await;
+        let mut join = b.join_owned(id);
+        let mut ctx = poll_fn(|cx| protocol::Future::poll(Pin::new(&mut join), cx, &mut *b))
+            .await
+            .ok()
+            .unwrap();
+
+        tasks.push(
+            pool.spawn_with_handle(async move {
+                for item in 0..MESSAGES {
+                    assert_eq!(receive::<u64, _>(&mut ctx).await, item);
+                }
+            })
+            .unwrap(),
+        );
+    }
+
+    tasks
+}
+
+async fn exchange(pool: &ThreadPool, a: &mut Connection, b: &mut Connection, contexts: u64) {
+    let (senders, receivers) = join(open(pool, a, contexts), accept(pool, b, contexts)).await;
+    join(join_all(senders), join_all(receivers)).await;
+}
+
+fn contexts(c: &mut Criterion) {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect_with(&pool, |builder| builder);
+
+    let mut group = c.benchmark_group("contexts");
+
+    for contexts in [1, 16, 256].iter() {
+        group.throughput(Throughput::Elements(contexts * MESSAGES));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(contexts),
+            contexts,
+            |bench, &contexts| bench.iter(|| block_on(exchange(&pool, &mut a, &mut b, contexts))),
+        );
+    }
+
+    group.finish();
+}
+
+/// Writes `BATCH` copies of `payload` to `a` and reads them back from `b`.
+async fn stream(a: &mut Connection, b: &mut Connection, payload: &[u8]) {
+    let writer = async {
+        for _ in 0..BATCH {
+            send(a, payload).await;
+        }
+        flush::<&[u8], _>(a).await;
+    };
+    let reader = async {
+        for _ in 0..BATCH {
+            receive::<Vec<u8>, _>(b).await;
+        }
+    };
+    join(writer, reader).await;
+}
+
+fn messages(c: &mut Criterion) {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect_with(&pool, |builder| builder);
+
+    let mut group = c.benchmark_group("messages");
+
+    for size in [0, 16, 256].iter() {
+        let payload = vec![0u8; *size];
+        group.throughput(Throughput::Elements(BATCH));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(size),
+            &payload,
+            |bench, payload| bench.iter(|| block_on(stream(&mut a, &mut b, payload))),
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, contexts, messages);
+criterion_main!(benches);
@@ -0,0 +1,343 @@
+use super::{
+    compression::DEFAULT_THRESHOLD, connect, encryption::PreSharedKey,
+    fragment::DEFAULT_FRAGMENT_SIZE, frames, framing::Frames, handshake::MIN_FRAME_SIZE,
+    keepalive::Keepalive, max_wire_frame_size, Bincode, BufferLimits, Coalesce, Compression,
+    Fingerprint, Format, FramedRead, FramedWrite, SerdeReadError, Timer, Transport, Unravel,
+    UnravelState,
+};
+use bytes::Bytes;
+use futures::{
+    io::{AsyncRead, AsyncWrite},
+    task::Spawn,
+    Sink, TryStream,
+};
+use std::{io, marker::PhantomData, sync::Arc, time::Duration};
+
+/// Panics if `limits` would leave the peer unable to write anything, which
+/// the peer would reject during the handshake anyway.
+fn check(limits: &BufferLimits) {
+    assert!(limits.window > 0, "the receive window must not be empty");
+    assert!(
+        limits.max_frame_size >= MIN_FRAME_SIZE,
+        "frames of at least {} bytes must be accepted",
+        MIN_FRAME_SIZE
+    );
+}
+
+/// Configures a connection before producing one of its ends as a `Coalesce`
+/// or `Unravel`.
+///
+/// Both ends of a connection must be configured with the same format, other
+/// settings may differ.
+pub struct TransportBuilder<S, C = Bincode> {
+    pub(super) spawner: S,
+    pub(super) limits: BufferLimits,
+    pub(super) outbound_capacity: usize,
+    pub(super) fingerprint: Option<Fingerprint>,
+    pub(super) compression: Option<Compression>,
+    pub(super) compression_threshold: usize,
+    pub(super) key: Option<PreSharedKey>,
+    pub(super) checksums: bool,
+    pub(super) fragment_size: usize,
+    pub(super) keepalive: Option<Keepalive>,
+    pub(super) timer: Option<Arc<dyn Timer>>,
+    _marker: PhantomData<C>,
+}
+
+impl<S> TransportBuilder<S> {
+    /// Creates a builder spawning the tasks that drive the connection on
+    /// `spawner`, serializing items with bincode.
+    pub fn new(spawner: S) -> Self {
+        TransportBuilder {
+            spawner,
+            limits: BufferLimits::default(),
+            outbound_capacity: 1,
+            fingerprint: None,
+            compression: None,
+            compression_threshold: DEFAULT_THRESHOLD,
+            key: None,
+            checksums: false,
+            fragment_size: DEFAULT_FRAGMENT_SIZE,
+            keepalive: None,
+            timer: None,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<S, C> TransportBuilder<S, C> {
+    /// Serializes items with the format `D` instead.
+    pub fn format<D: Format>(self) -> TransportBuilder<S, D> {
+        TransportBuilder {
+            spawner: self.spawner,
+            limits: self.limits,
+            outbound_capacity: self.outbound_capacity,
+            fingerprint: self.fingerprint,
+            compression: self.compression,
+            compression_threshold: self.compression_threshold,
+            key: self.key,
+            checksums: self.checksums,
+            fragment_size: self.fragment_size,
+            keepalive: self.keepalive,
+            timer: self.timer,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Spawns the tasks that drive the connection on `spawner` instead.
+    pub fn spawner<T>(self, spawner: T) -> TransportBuilder<T, C> {
+        TransportBuilder {
+            spawner,
+            limits: self.limits,
+            outbound_capacity: self.outbound_capacity,
+            fingerprint: self.fingerprint,
+            compression: self.compression,
+            compression_threshold: self.compression_threshold,
+            key: self.key,
+            checksums: self.checksums,
+            fragment_size: self.fragment_size,
+            keepalive: self.keepalive,
+            timer: self.timer,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Sets the limits on buffering for contexts the peer writes to before
+    /// they are joined, along with the receive window and maximum frame size.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the window is empty or the maximum frame size is below 32
+    /// bytes, either of which would leave the peer unable to write anything.
+    pub fn limits(mut self, limits: BufferLimits) -> Self {
+        check(&limits);
+        self.limits = limits;
+        self
+    }
+
+    /// Sets the largest frame accepted from the peer, in bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is below 32, too few for the peer to write anything.
+    pub fn max_frame_size(mut self, bytes: usize) -> Self {
+        self.limits.max_frame_size = bytes;
+        check(&self.limits);
+        self
+    }
+
+    /// Sets the largest item accepted from the peer once reassembled from its
+    /// fragments, in bytes.
+    pub fn max_message_size(mut self, bytes: usize) -> Self {
+        self.limits.max_message_size = bytes;
+        self
+    }
+
+    /// Sets the largest payload written in a single frame, in bytes. Larger
+    /// items are split into fragments of this size, which are interleaved
+    /// with the frames of other contexts and reassembled by the peer before
+    /// they are read. Fragments are made smaller still if the peer accepts
+    /// no frames this large. Defaults to 64 KiB.
+    pub fn fragment_size(mut self, bytes: usize) -> Self {
+        self.fragment_size = bytes;
+        self
+    }
+
+    /// Sets the number of frames each context may queue for the sink before
+    /// its writers have to wait, counting every fragment of an item.
+    /// Defaults to one.
+    pub fn outbound_capacity(mut self, frames: usize) -> Self {
+        self.outbound_capacity = frames;
+        self
+    }
+
+    /// Rejects peers that supply a different fingerprint during the
+    /// handshake.
+    pub fn fingerprint(mut self, fingerprint: Fingerprint) -> Self {
+        self.fingerprint = Some(fingerprint);
+        self
+    }
+
+    /// Compresses the items written to the peer with `compression`, provided
+    /// the peer announces it can decode it. Frames exchanged by the transport
+    /// itself and items smaller than the compression threshold are always
+    /// written uncompressed.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Sets the size in bytes below which items are written uncompressed.
+    /// Defaults to 512.
+    pub fn compression_threshold(mut self, bytes: usize) -> Self {
+        self.compression_threshold = bytes;
+        self
+    }
+
+    /// Encrypts and authenticates every frame following the handshake with
+    /// `key`, which the peer must have been configured with as well. Frames
+    /// failing authentication end the connection with
+    /// `SerdeReadError::Authentication`.
+    #[cfg(feature = "encryption")]
+    pub fn pre_shared_key(mut self, key: PreSharedKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// Ends every frame exchanged after the handshake in a CRC32C checksum,
+    /// verified before the frame is routed. A corrupted frame ends the
+    /// connection with `SerdeReadError::Corrupt`. Checksums are used in both
+    /// directions if either end enables them.
+    pub fn checksums(mut self, enabled: bool) -> Self {
+        self.checksums = enabled;
+        self
+    }
+
+    /// Pings the peer once `interval` has passed since it answered the
+    /// previous ping, using `timer` to wait. Should a ping go unanswered for
+    /// `timeout`, the connection ends with `SerdeReadError::Timeout`, which
+    /// every context then reads. The peer answers pings whether or not it
+    /// sends any itself, and the latest round-trip time is available from
+    /// `Transport::round_trip_time`.
+    pub fn keepalive<T: Timer + 'static>(
+        mut self,
+        timer: T,
+        interval: Duration,
+        timeout: Duration,
+    ) -> Self {
+        self.keepalive = Some(Keepalive {
+            timer: Arc::new(timer),
+            interval,
+            timeout,
+        });
+        self
+    }
+
+    /// Waits with `timer` for the buffers of contexts that were never joined
+    /// to expire, so that they are dropped even while the connection is
+    /// idle. Defaults to the timer used for keepalive pings, if any.
+    pub fn timer<T: Timer + 'static>(mut self, timer: T) -> Self {
+        self.timer = Some(Arc::new(timer));
+        self
+    }
+}
+
+impl<S: Clone + Send + Spawn + 'static, C: Format> TransportBuilder<S, C> {
+    /// Returns the end of a connection over `stream` and `sink` that
+    /// coalesces the protocol type `P`.
+    pub fn coalesce<T, U, P>(self, stream: T, sink: U) -> Coalesce<T, U, S, P, C>
+    where
+        T: TryStream + Unpin + Send + 'static,
+        U: Sink<Vec<u8>> + Send + 'static,
+        P: protocol::Coalesce<Transport<S, T::Error, U::Error, P, C>>,
+        P::Future: Unpin,
+        T::Ok: Into<Bytes>,
+        T::Error: Send + Sync,
+        U::Error: Send + Sync,
+    {
+        self.coalesce_frames::<T, _, _, _>(frames(stream), sink)
+    }
+
+    /// Returns the end of a connection over `stream` and `sink` that
+    /// unravels `item`.
+    pub fn unravel<T, U, P>(self, stream: T, sink: U, item: P) -> Unravel<T, U, S, P, C>
+    where
+        T: TryStream + Unpin + Send + 'static,
+        U: Sink<Vec<u8>> + Send + 'static,
+        P: protocol::Unravel<Transport<S, T::Error, U::Error, P, C>>,
+        P::Target: Unpin,
+        P::Finalize: Unpin,
+        T::Ok: Into<Bytes>,
+        T::Error: Send + Sync,
+        U::Error: Send + Sync,
+    {
+        self.unravel_frames::<T, _, _, _>(frames(stream), sink, item)
+    }
+
+    /// Like `coalesce`, but reading and writing length-delimited frames over
+    /// a byte stream such as a socket. Length prefixes larger than the
+    /// maximum frame size are rejected with `SerdeReadError::Framing`.
+    pub fn coalesce_io<R, W, P>(
+        self,
+        reader: R,
+        writer: W,
+    ) -> Coalesce<FramedRead<R>, FramedWrite<W>, S, P, C>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+        P: protocol::Coalesce<Transport<S, io::Error, io::Error, P, C>>,
+        P::Future: Unpin,
+    {
+        let limit = max_wire_frame_size(self.limits.max_frame_size, self.key.is_some());
+        let frames = Frames(FramedRead::new(reader, limit));
+        self.coalesce_frames::<FramedRead<R>, _, _, _>(frames, FramedWrite::new(writer))
+    }
+
+    /// Like `unravel`, but reading and writing length-delimited frames over
+    /// a byte stream such as a socket. Length prefixes larger than the
+    /// maximum frame size are rejected with `SerdeReadError::Framing`.
+    pub fn unravel_io<R, W, P>(
+        self,
+        reader: R,
+        writer: W,
+        item: P,
+    ) -> Unravel<FramedRead<R>, FramedWrite<W>, S, P, C>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+        P: protocol::Unravel<Transport<S, io::Error, io::Error, P, C>>,
+        P::Target: Unpin,
+        P::Finalize: Unpin,
+    {
+        let limit = max_wire_frame_size(self.limits.max_frame_size, self.key.is_some());
+        let frames = Frames(FramedRead::new(reader, limit));
+        self.unravel_frames::<FramedRead<R>, _, _, _>(frames, FramedWrite::new(writer), item)
+    }
+
+    /// Coalesces from a stream of frames whose errors were already wrapped,
+    /// on behalf of a `Coalesce` over `T`.
+    fn coalesce_frames<T, F, U, P>(self, frames: F, sink: U) -> Coalesce<T, U, S, P, C>
+    where
+        T: TryStream + 'static,
+        F: TryStream<Ok = Bytes, Error = SerdeReadError<Arc<T::Error>>> + Unpin + Send + 'static,
+        U: Sink<Vec<u8>> + Send + 'static,
+        P: protocol::Coalesce<Transport<S, T::Error, U::Error, P, C>>,
+        P::Future: Unpin,
+        T::Error: Send + Sync,
+        U::Error: Send + Sync,
+    {
+        let (transport, initializer, handshake, agreement) = connect(frames, sink, self, 2);
+
+        Coalesce {
+            transport,
+            initializer: Some(initializer),
+            handshake,
+            agreement: Some(agreement),
+            fut: P::coalesce(),
+        }
+    }
+
+    /// Unravels over a stream of frames whose errors were already wrapped,
+    /// on behalf of an `Unravel` over `T`.
+    fn unravel_frames<T, F, U, P>(self, frames: F, sink: U, item: P) -> Unravel<T, U, S, P, C>
+    where
+        T: TryStream + 'static,
+        F: TryStream<Ok = Bytes, Error = SerdeReadError<Arc<T::Error>>> + Unpin + Send + 'static,
+        U: Sink<Vec<u8>> + Send + 'static,
+        P: protocol::Unravel<Transport<S, T::Error, U::Error, P, C>>,
+        P::Target: Unpin,
+        P::Finalize: Unpin,
+        T::Error: Send + Sync,
+        U::Error: Send + Sync,
+    {
+        let (transport, initializer, handshake, agreement) = connect(frames, sink, self, 1);
+
+        Unravel {
+            transport,
+            initializer: Some(initializer),
+            handshake,
+            agreement: Some(agreement),
+            fut: UnravelState::Target(item.unravel()),
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use bytes::Bytes;
+use crc32c::crc32c;
+
+/// Number of bytes the checksum adds to every frame.
+pub(super) const CHECKSUM_LEN: usize = 4;
+
+/// Appends the CRC32C of `frame` to it, in little-endian byte order.
+pub(super) fn append(frame: &mut Vec<u8>) {
+    let checksum = crc32c(frame);
+    frame.extend_from_slice(&checksum.to_le_bytes());
+}
+
+/// Strips the checksum off the end of `frame`, returning the frame without
+/// it if it matches, or the frame as is if it doesn't.
+pub(super) fn verify(frame: Bytes) -> Result<Bytes, Bytes> {
+    let length = match frame.len().checked_sub(CHECKSUM_LEN) {
+        Some(length) => length,
+        None => return Err(frame),
+    };
+
+    let mut checksum = [0; CHECKSUM_LEN];
+    checksum.copy_from_slice(&frame[length..]);
+
+    if crc32c(&frame[..length]) == u32::from_le_bytes(checksum) {
+        Ok(frame.slice(..length))
+    } else {
+        Err(frame)
+    }
+}
@@ -0,0 +1,180 @@
+use std::fmt::{self, Display, Formatter};
+
+/// Flag marking the payload of a frame as written as is.
+pub(super) const UNCOMPRESSED: u8 = 0;
+
+/// Payloads below this size are written uncompressed by default.
+pub(super) const DEFAULT_THRESHOLD: usize = 512;
+
+/// An algorithm used to compress the payloads of frames written to the peer.
+///
+/// Compression is chosen separately for either direction of a connection: an
+/// end only compresses frames with the algorithm it was configured with if
+/// the peer announced it can decode it during the handshake, and every end
+/// can decode all the algorithms enabled through the corresponding cargo
+/// features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Zstandard at its default compression level, requiring the `zstd`
+    /// feature.
+    #[cfg(feature = "zstd")]
+    Zstd,
+    /// LZ4, requiring the `lz4` feature. Compresses less than zstd, but
+    /// takes considerably less time to do so.
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Compression {
+    /// Returns the algorithms this build can decode.
+    pub(super) fn supported() -> Vec<Compression> {
+        vec![
+            #[cfg(feature = "zstd")]
+            Compression::Zstd,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+        ]
+    }
+
+    /// Returns the name identifying the algorithm in the handshake.
+    pub(super) fn name(self) -> &'static str {
+        match self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => "zstd",
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => "lz4",
+        }
+    }
+
+    /// Returns the flag marking payloads compressed with this algorithm.
+    fn flag(self) -> u8 {
+        match self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => 1,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => 2,
+        }
+    }
+
+    fn from_flag(flag: u8) -> Option<Self> {
+        Compression::supported()
+            .into_iter()
+            .find(|compression| compression.flag() == flag)
+    }
+
+    #[cfg_attr(not(any(feature = "zstd", feature = "lz4")), allow(unused_variables))]
+    fn compress(self, payload: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::bulk::compress(payload, 0).ok(),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Some(lz4_flex::compress_prepend_size(payload)),
+        }
+    }
+
+    /// Decompresses `payload`, failing if it doesn't hold valid data or
+    /// would decompress to more than `limit` bytes. Zstandard frames must
+    /// declare their decompressed size, as every frame written by this crate
+    /// does.
+    #[cfg_attr(not(any(feature = "zstd", feature = "lz4")), allow(unused_variables))]
+    fn decompress(self, payload: &[u8], limit: usize) -> Option<Vec<u8>> {
+        match self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                // Allocate only what the frame declares, rather than the
+                // limit, for every frame.
+                let size = zstd::zstd_safe::get_frame_content_size(payload).ok()??;
+
+                if size > limit as u64 {
+                    return None;
+                }
+
+                zstd::bulk::decompress(payload, size as usize).ok()
+            }
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                let (size, data) = lz4_flex::block::uncompressed_size(payload).ok()?;
+
+                if size > limit {
+                    return None;
+                }
+
+                lz4_flex::block::decompress(data, size).ok()
+            }
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Compresses the payload of `frame`, which starts with `offset` bytes of
+/// header ending in its flags, if it is at least `threshold` bytes long and
+/// compressing it makes it any smaller.
+pub(super) fn compress(
+    compression: Option<Compression>,
+    threshold: usize,
+    frame: Vec<u8>,
+    offset: usize,
+) -> Vec<u8> {
+    let compression = match compression {
+        Some(compression) if frame.len() - offset >= threshold => compression,
+        _ => return frame,
+    };
+
+    match compression.compress(&frame[offset..]) {
+        Some(payload) if payload.len() < frame.len() - offset => {
+            let mut data = Vec::with_capacity(offset + payload.len());
+            data.extend_from_slice(&frame[..offset - 1]);
+            data.push(compression.flag());
+            data.extend_from_slice(&payload);
+            data
+        }
+        _ => frame,
+    }
+}
+
+/// Decompresses a received payload as indicated by `flag`, returning `None`
+/// for flags this build doesn't understand or data that fails to decompress
+/// to at most `limit` bytes.
+pub(super) fn decompress(flag: u8, payload: &[u8], limit: usize) -> Option<Vec<u8>> {
+    Compression::from_flag(flag)?.decompress(payload, limit)
+}
+
+#[cfg(all(test, feature = "zstd"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zstd_frames_decompress_to_their_declared_size() {
+        let data = vec![7; 1000];
+        let payload = Compression::Zstd.compress(&data).unwrap();
+
+        // Far more than could ever be allocated up front.
+        assert_eq!(Compression::Zstd.decompress(&payload, 1 << 62), Some(data));
+        assert_eq!(Compression::Zstd.decompress(&payload, 999), None);
+    }
+
+    #[test]
+    fn zstd_frames_declaring_huge_sizes_are_rejected() {
+        // A frame header declaring 1 TiB of content in an 8 byte field,
+        // followed by an empty raw block.
+        let mut payload = vec![0x28, 0xb5, 0x2f, 0xfd, 0xe0];
+        payload.extend_from_slice(&(1u64 << 40).to_le_bytes());
+        payload.extend_from_slice(&[0x01, 0x00, 0x00]);
+
+        assert_eq!(Compression::Zstd.decompress(&payload, 16 << 20), None);
+    }
+
+    #[test]
+    fn zstd_frames_of_unknown_size_are_rejected() {
+        // A frame header without a content size, followed by an empty raw
+        // block.
+        let payload = [0x28, 0xb5, 0x2f, 0xfd, 0x00, 0x00, 0x01, 0x00, 0x00];
+
+        assert_eq!(Compression::Zstd.decompress(&payload, 16 << 20), None);
+    }
+}
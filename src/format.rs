@@ -0,0 +1,87 @@
+use core_error::Error;
+use serde::{Deserialize, Serialize};
+
+/// A serialization format used to encode the items written to and read from
+/// a `Transport`.
+///
+/// Both ends of a connection must use the same format. Control frames
+/// exchanged by the transport itself are always encoded with bincode.
+pub trait Format {
+    type Error: Error + Send + Sync + 'static;
+
+    /// Identifies the format in the handshake, so that peers using
+    /// different formats are turned away.
+    const NAME: &'static str;
+
+    /// Appends the serialized form of `item` to `buffer`.
+    fn serialize_into<T: Serialize>(buffer: &mut Vec<u8>, item: &T) -> Result<(), Self::Error>;
+
+    /// Returns the number of bytes `item` is expected to serialize to, which
+    /// is used to size frame buffers up front. Formats that can't compute
+    /// this cheaply may return zero.
+    fn size_hint<T: Serialize>(_item: &T) -> usize {
+        0
+    }
+
+    fn deserialize<'de, T: Deserialize<'de>>(data: &'de [u8]) -> Result<T, Self::Error>;
+}
+
+/// The compact binary format provided by `bincode`. This is the default.
+pub struct Bincode;
+
+impl Format for Bincode {
+    type Error = bincode::Error;
+
+    const NAME: &'static str = "bincode";
+
+    fn serialize_into<T: Serialize>(buffer: &mut Vec<u8>, item: &T) -> Result<(), Self::Error> {
+        bincode::serialize_into(buffer, item)
+    }
+
+    fn size_hint<T: Serialize>(item: &T) -> usize {
+        bincode::serialized_size(item).unwrap_or(0) as usize
+    }
+
+    fn deserialize<'de, T: Deserialize<'de>>(data: &'de [u8]) -> Result<T, Self::Error> {
+        bincode::deserialize(data)
+    }
+}
+
+/// Self-describing JSON, mostly useful for inspecting traffic while
+/// debugging.
+#[cfg(feature = "json")]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Format for Json {
+    type Error = serde_json::Error;
+
+    const NAME: &'static str = "json";
+
+    fn serialize_into<T: Serialize>(buffer: &mut Vec<u8>, item: &T) -> Result<(), Self::Error> {
+        serde_json::to_writer(buffer, item)
+    }
+
+    fn deserialize<'de, T: Deserialize<'de>>(data: &'de [u8]) -> Result<T, Self::Error> {
+        serde_json::from_slice(data)
+    }
+}
+
+/// The Concise Binary Object Representation as specified in RFC 7049.
+#[cfg(feature = "cbor")]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Format for Cbor {
+    type Error = serde_cbor::Error;
+
+    const NAME: &'static str = "cbor";
+
+    fn serialize_into<T: Serialize>(buffer: &mut Vec<u8>, item: &T) -> Result<(), Self::Error> {
+        serde_cbor::to_writer(buffer, item)
+    }
+
+    fn deserialize<'de, T: Deserialize<'de>>(data: &'de [u8]) -> Result<T, Self::Error> {
+        serde_cbor::from_slice(data)
+    }
+}
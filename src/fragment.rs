@@ -0,0 +1,104 @@
+use super::checksum;
+use std::mem::take;
+
+/// Flag marking a frame as a fragment of an item that is continued by the
+/// next frame written to the same context.
+pub(super) const MORE: u8 = 0x80;
+
+/// Largest payload written in a single frame by default, in bytes.
+pub(super) const DEFAULT_FRAGMENT_SIZE: usize = 64 << 10;
+
+/// The frames an item is written in, each carrying at most `size` bytes of
+/// its payload.
+///
+/// Every fragment repeats the header, and all but the last are flagged with
+/// `MORE`, so the peer can reassemble the item before it is read. Fragments
+/// are sliced off one at a time as the scheduler writes them, so an item is
+/// never held twice over, and the last reuses the buffer of the item.
+pub(super) struct Fragments {
+    frame: Vec<u8>,
+    /// Length of the header, ending in its flags.
+    offset: usize,
+    size: usize,
+    /// Start of the payload yet to be written.
+    position: usize,
+    checksums: bool,
+}
+
+impl Fragments {
+    /// Splits `frame`, which starts with `offset` bytes of header ending in
+    /// its flags, ending each fragment in a checksum if `checksums` is set.
+    pub(super) fn new(frame: Vec<u8>, offset: usize, size: usize, checksums: bool) -> Self {
+        Fragments {
+            frame,
+            offset,
+            size: size.max(1),
+            position: offset,
+            checksums,
+        }
+    }
+
+    /// Writes `frame` as it is, such as a control frame.
+    pub(super) fn whole(frame: Vec<u8>) -> Self {
+        let offset = frame.len();
+        Fragments::new(frame, offset, 1, false)
+    }
+
+    /// Returns the number of fragments left to write.
+    pub(super) fn len(&self) -> usize {
+        (self.frame.len() - self.position)
+            .div_ceil(self.size)
+            .max(1)
+    }
+
+    /// Slices off the next fragment, returning it along with whether it was
+    /// the last.
+    pub(super) fn next(&mut self) -> (Vec<u8>, bool) {
+        let last = self.frame.len() - self.position <= self.size;
+
+        let mut fragment = if last {
+            let mut frame = take(&mut self.frame);
+            frame.drain(self.offset..self.position);
+            frame
+        } else {
+            let end = self.position + self.size;
+            let mut fragment = Vec::with_capacity(self.offset + self.size);
+            fragment.extend_from_slice(&self.frame[..self.offset]);
+            fragment[self.offset - 1] |= MORE;
+            fragment.extend_from_slice(&self.frame[self.position..end]);
+            self.position = end;
+            fragment
+        };
+
+        if self.checksums {
+            checksum::append(&mut fragment);
+        }
+
+        (fragment, last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fragments_are_sliced_off_one_at_a_time() {
+        let mut fragments = Fragments::new(vec![9, 0, 1, 2, 3, 4, 5], 2, 2, false);
+
+        assert_eq!(fragments.len(), 3);
+        assert_eq!(fragments.next(), (vec![9, MORE, 1, 2], false));
+        assert_eq!(fragments.len(), 2);
+        assert_eq!(fragments.next(), (vec![9, MORE, 3, 4], false));
+        assert_eq!(fragments.next(), (vec![9, 0, 5], true));
+    }
+
+    #[test]
+    fn small_items_are_written_whole() {
+        let mut fragments = Fragments::new(vec![9, 0, 1, 2], 2, 2, false);
+
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(fragments.next(), (vec![9, 0, 1, 2], true));
+        assert_eq!(Fragments::whole(vec![]).next(), (vec![], true));
+    }
+}
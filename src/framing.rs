@@ -0,0 +1,230 @@
+use super::SerdeReadError;
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{
+    io::{AsyncRead, AsyncWrite},
+    ready, Sink, Stream,
+};
+use std::{
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use thiserror::Error;
+
+/// Size of the big-endian length that prefixes every frame.
+const PREFIX: usize = 4;
+
+/// Size of the reads issued to the underlying reader. Frames are read in
+/// chunks of this size rather than all at once, so the buffer only grows as
+/// data actually arrives, whatever length the prefix claims.
+const CHUNK: usize = 8 * 1024;
+
+/// Amount of written data buffered before it is flushed to the underlying
+/// writer.
+const HIGH_WATER: usize = 64 * 1024;
+
+/// An error in the length-delimited framing of a byte stream.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum FramingError {
+    #[error("frame length of {0} bytes exceeds the maximum frame size")]
+    Oversized(usize),
+    #[error("stream ended in the middle of a frame")]
+    Truncated,
+}
+
+enum ReadError {
+    Io(io::Error),
+    Framing(FramingError),
+}
+
+/// Splits a byte stream into frames, each prefixed by its length as a 4-byte
+/// big-endian integer.
+///
+/// Used as a standalone stream, framing errors are reported as
+/// `io::ErrorKind::InvalidData`. The stream ends after the first error.
+pub struct FramedRead<R> {
+    reader: R,
+    buffer: BytesMut,
+    max_frame_size: usize,
+    done: bool,
+}
+
+impl<R: AsyncRead + Unpin> FramedRead<R> {
+    pub fn new(reader: R, max_frame_size: usize) -> Self {
+        FramedRead {
+            reader,
+            buffer: BytesMut::new(),
+            max_frame_size,
+            done: false,
+        }
+    }
+
+    fn poll_frame(&mut self, cx: &mut Context) -> Poll<Option<Result<Bytes, ReadError>>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+
+        let frame = ready!(self.poll_read_frame(cx));
+
+        if !matches!(frame, Some(Ok(_))) {
+            self.done = true;
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn poll_read_frame(&mut self, cx: &mut Context) -> Poll<Option<Result<Bytes, ReadError>>> {
+        loop {
+            if self.buffer.len() >= PREFIX {
+                let mut length = [0; PREFIX];
+                length.copy_from_slice(&self.buffer[..PREFIX]);
+                let length = u32::from_be_bytes(length) as usize;
+
+                if length > self.max_frame_size {
+                    return Poll::Ready(Some(Err(ReadError::Framing(FramingError::Oversized(
+                        length,
+                    )))));
+                }
+
+                if self.buffer.len() >= PREFIX + length {
+                    let mut frame = self.buffer.split_to(PREFIX + length);
+                    frame.advance(PREFIX);
+                    return Poll::Ready(Some(Ok(frame.freeze())));
+                }
+            }
+
+            let start = self.buffer.len();
+            self.buffer.resize(start + CHUNK, 0);
+
+            let read = Pin::new(&mut self.reader).poll_read(cx, &mut self.buffer[start..]);
+
+            let read = match read {
+                Poll::Ready(Ok(read)) => read,
+                Poll::Ready(Err(e)) => {
+                    self.buffer.truncate(start);
+                    return Poll::Ready(Some(Err(ReadError::Io(e))));
+                }
+                Poll::Pending => {
+                    self.buffer.truncate(start);
+                    return Poll::Pending;
+                }
+            };
+
+            self.buffer.truncate(start + read);
+
+            if read == 0 {
+                return Poll::Ready(if self.buffer.is_empty() {
+                    None
+                } else {
+                    Some(Err(ReadError::Framing(FramingError::Truncated)))
+                });
+            }
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for FramedRead<R> {
+    type Item = Result<Bytes, io::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        Poll::Ready(ready!(self.poll_frame(cx)).map(|frame| {
+            frame.map_err(|e| match e {
+                ReadError::Io(e) => e,
+                ReadError::Framing(e) => io::Error::new(io::ErrorKind::InvalidData, e),
+            })
+        }))
+    }
+}
+
+/// Frames read by a `FramedRead`, with framing errors reported as
+/// `SerdeReadError::Framing`.
+pub(super) struct Frames<R>(pub(super) FramedRead<R>);
+
+impl<R: AsyncRead + Unpin> Stream for Frames<R> {
+    type Item = Result<Bytes, SerdeReadError<Arc<io::Error>>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        Poll::Ready(ready!(self.0.poll_frame(cx)).map(|frame| {
+            frame.map_err(|e| match e {
+                ReadError::Io(e) => SerdeReadError::Stream(Arc::new(e)),
+                ReadError::Framing(e) => SerdeReadError::Framing(e),
+            })
+        }))
+    }
+}
+
+/// Writes frames to a byte stream, prefixing each with its length as a
+/// 4-byte big-endian integer.
+pub struct FramedWrite<W> {
+    writer: W,
+    buffer: Vec<u8>,
+    written: usize,
+}
+
+impl<W: AsyncWrite + Unpin> FramedWrite<W> {
+    pub fn new(writer: W) -> Self {
+        FramedWrite {
+            writer,
+            buffer: vec![],
+            written: 0,
+        }
+    }
+
+    fn poll_write_buffer(&mut self, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        while self.written < self.buffer.len() {
+            let written =
+                ready!(Pin::new(&mut self.writer).poll_write(cx, &self.buffer[self.written..]))?;
+
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+
+            self.written += written;
+        }
+
+        self.buffer.clear();
+        self.written = 0;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Sink<Vec<u8>> for FramedWrite<W> {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        if self.buffer.len() >= HIGH_WATER {
+            ready!(self.poll_write_buffer(cx))?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), io::Error> {
+        if item.len() > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                FramingError::Oversized(item.len()),
+            ));
+        }
+
+        self.buffer
+            .extend_from_slice(&(item.len() as u32).to_be_bytes());
+        self.buffer.extend_from_slice(&item);
+
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        ready!(self.poll_write_buffer(cx))?;
+
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), io::Error>> {
+        ready!(self.poll_write_buffer(cx))?;
+
+        Pin::new(&mut self.writer).poll_close(cx)
+    }
+}
@@ -0,0 +1,317 @@
+use super::{BufferLimits, Compression, Header};
+use bincode::{deserialize as from_slice, serialize_into};
+use serde::{Deserialize, Serialize};
+use std::{
+    any::type_name,
+    fmt::{self, Display, Formatter},
+};
+use thiserror::Error;
+
+/// Identifies the first frame of a connection as a handshake of this
+/// transport.
+const MAGIC: [u8; 4] = *b"PMVT";
+
+/// Version of the wire format, bumped whenever the frames exchanged by the
+/// two ends change incompatibly.
+const VERSION: u8 = 7;
+
+/// Smallest frame size either end may accept, leaving room for the longest
+/// handle and a byte of flags ahead of at least one byte of payload, and for
+/// every control frame.
+pub(super) const MIN_FRAME_SIZE: usize = 32;
+
+/// The reason a connection was rejected during the handshake.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum Incompatibility {
+    #[error("peer is not speaking this protocol")]
+    Magic,
+    #[error("peer uses wire format version {0}, expected version {}", VERSION)]
+    Version(u8),
+    #[error("received a malformed handshake")]
+    Malformed,
+    #[error("peer serializes items as {0}")]
+    Codec(String),
+    #[error("peer supports none of the available frame header formats")]
+    Header,
+    #[error("peer does not support flow control")]
+    FlowControl,
+    #[error("peer speaks a different protocol: {0}")]
+    Fingerprint(Fingerprint),
+    #[error("only one end of the connection encrypts its frames")]
+    Encryption,
+    #[error("peer lets no frames be written before granting credit")]
+    Window,
+    #[error("peer accepts frames of at most {0} bytes, too few to carry any item")]
+    FrameSize(u64),
+}
+
+/// Identifies the protocol type spoken over a connection, so that peers
+/// speaking different ones are turned away during the handshake rather than
+/// failing to deserialize some frame later on.
+///
+/// A connection is only checked if both ends supply a fingerprint.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Fingerprint(String);
+
+impl Fingerprint {
+    /// Derives a fingerprint from the name of `P`. Type names are not
+    /// guaranteed to be stable across compiler versions, so both ends should
+    /// be built with the same toolchain.
+    pub fn of<P: ?Sized>() -> Self {
+        Fingerprint(type_name::<P>().to_owned())
+    }
+
+    /// Creates a fingerprint from a hash of the schema of the protocol type,
+    /// computed by the user.
+    pub fn from_hash(hash: u64) -> Self {
+        Fingerprint(format!("{:016x}", hash))
+    }
+}
+
+impl Display for Fingerprint {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Optional features of the transport supported by either end.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Capabilities {
+    /// Compression algorithms the sender can decode.
+    compression: Vec<String>,
+    /// Whether the sender grants credit for the frames it reads.
+    flow_control: bool,
+    /// Number of frames the sender lets the peer write to a context before
+    /// waiting for credit.
+    window: u32,
+    /// Largest frame the sender accepts, in bytes.
+    max_frame_size: u64,
+    /// Largest item the sender accepts once reassembled from its fragments,
+    /// in bytes.
+    max_message_size: u64,
+    /// Prefix of the nonces the sender encrypts the frames following the
+    /// handshake with, if it encrypts them.
+    nonce_prefix: Option<[u8; 16]>,
+    /// Whether the sender wants every frame following the handshake to end
+    /// in a checksum.
+    checksums: bool,
+}
+
+/// Everything in the handshake following the magic number and version,
+/// whose layout may change between versions.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Body {
+    codec: String,
+    fingerprint: Option<Fingerprint>,
+    headers: Vec<u8>,
+    capabilities: Capabilities,
+}
+
+/// The first frame sent by either end of a connection, describing how it
+/// expects the frames that follow to be encoded.
+#[derive(Debug, Clone)]
+pub(super) struct Handshake {
+    body: Body,
+    /// The algorithm this end would like to compress the frames it writes
+    /// with, which is not sent to the peer.
+    compression: Option<Compression>,
+}
+
+/// The terms both ends of a connection settled on during the handshake.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Agreement {
+    pub(super) header: Header,
+    /// Number of frames that may be written to a context before waiting for
+    /// the peer to grant credit.
+    pub(super) window: u32,
+    /// Largest frame the peer accepts, in bytes.
+    pub(super) max_frame_size: usize,
+    /// Largest item the peer accepts once reassembled, in bytes.
+    pub(super) max_message_size: usize,
+    /// The algorithm to compress frames written to the peer with, if any.
+    pub(super) compression: Option<Compression>,
+    /// Prefix of the nonces the peer encrypts its frames with, if both ends
+    /// encrypt them.
+    pub(super) nonce_prefix: Option<[u8; 16]>,
+    /// Whether frames in either direction end in a checksum, which is the
+    /// case if either end asked for it.
+    pub(super) checksums: bool,
+}
+
+impl Handshake {
+    pub(super) fn new(codec: &str, limits: &BufferLimits) -> Self {
+        Handshake {
+            body: Body {
+                codec: codec.to_owned(),
+                fingerprint: None,
+                headers: Header::SUPPORTED
+                    .iter()
+                    .map(|header| *header as u8)
+                    .collect(),
+                capabilities: Capabilities {
+                    compression: Compression::supported()
+                        .into_iter()
+                        .map(|compression| compression.name().to_owned())
+                        .collect(),
+                    flow_control: true,
+                    window: limits.window,
+                    max_frame_size: limits.max_frame_size as u64,
+                    max_message_size: limits.max_message_size as u64,
+                    nonce_prefix: None,
+                    checksums: false,
+                },
+            },
+            compression: None,
+        }
+    }
+
+    pub(super) fn set_compression(&mut self, compression: Compression) {
+        self.compression = Some(compression);
+    }
+
+    /// Announces that the frames following the handshake are encrypted
+    /// using nonces starting with `prefix`.
+    pub(super) fn set_nonce_prefix(&mut self, prefix: [u8; 16]) {
+        self.body.capabilities.nonce_prefix = Some(prefix);
+    }
+
+    /// Asks for every frame following the handshake to end in a checksum.
+    pub(super) fn set_checksums(&mut self) {
+        self.body.capabilities.checksums = true;
+    }
+
+    pub(super) fn set_fingerprint(&mut self, fingerprint: Fingerprint) {
+        self.body.fingerprint = Some(fingerprint);
+    }
+
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION);
+        serialize_into(&mut data, &self.body).expect("handshakes are always serializable");
+        data
+    }
+
+    pub(super) fn decode(data: &[u8]) -> Result<Self, Incompatibility> {
+        if data.get(..4) != Some(&MAGIC[..]) {
+            return Err(Incompatibility::Magic);
+        }
+
+        match data.get(4) {
+            Some(&VERSION) => {}
+            Some(version) => return Err(Incompatibility::Version(*version)),
+            None => return Err(Incompatibility::Malformed),
+        }
+
+        Ok(Handshake {
+            body: from_slice(&data[5..]).map_err(|_| Incompatibility::Malformed)?,
+            compression: None,
+        })
+    }
+
+    /// Checks the handshake received from the peer against this one,
+    /// settling on the terms both ends will use.
+    pub(super) fn agree(&self, peer: &Handshake) -> Result<Agreement, Incompatibility> {
+        if peer.body.codec != self.body.codec {
+            return Err(Incompatibility::Codec(peer.body.codec.clone()));
+        }
+
+        if let (Some(ours), Some(theirs)) = (&self.body.fingerprint, &peer.body.fingerprint) {
+            if ours != theirs {
+                return Err(Incompatibility::Fingerprint(theirs.clone()));
+            }
+        }
+
+        if !peer.body.capabilities.flow_control {
+            return Err(Incompatibility::FlowControl);
+        }
+
+        if peer.body.capabilities.window == 0 {
+            return Err(Incompatibility::Window);
+        }
+
+        let max_frame_size = peer.body.capabilities.max_frame_size;
+
+        if max_frame_size < MIN_FRAME_SIZE as u64 {
+            return Err(Incompatibility::FrameSize(max_frame_size));
+        }
+
+        let nonce_prefix = peer.body.capabilities.nonce_prefix;
+
+        if self.body.capabilities.nonce_prefix.is_some() != nonce_prefix.is_some() {
+            return Err(Incompatibility::Encryption);
+        }
+
+        let header = Header::SUPPORTED
+            .iter()
+            .rev()
+            .find(|header| peer.body.headers.contains(&(**header as u8)))
+            .copied()
+            .ok_or(Incompatibility::Header)?;
+
+        let compression = self.compression.filter(|compression| {
+            peer.body
+                .capabilities
+                .compression
+                .iter()
+                .any(|name| name == compression.name())
+        });
+
+        Ok(Agreement {
+            header,
+            window: peer.body.capabilities.window,
+            max_frame_size: max_frame_size.min(usize::MAX as u64) as usize,
+            max_message_size: peer
+                .body
+                .capabilities
+                .max_message_size
+                .min(usize::MAX as u64) as usize,
+            compression,
+            nonce_prefix,
+            checksums: self.body.capabilities.checksums || peer.body.capabilities.checksums,
+        })
+    }
+
+    /// Returns the largest frame this end accepts, in bytes.
+    pub(super) fn max_frame_size(&self) -> usize {
+        self.body.capabilities.max_frame_size as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agree(limits: BufferLimits) -> Result<Agreement, Incompatibility> {
+        let peer = Handshake::new("bincode", &limits).encode();
+
+        Handshake::new("bincode", &BufferLimits::default()).agree(&Handshake::decode(&peer)?)
+    }
+
+    #[test]
+    fn empty_windows_are_rejected() {
+        let limits = BufferLimits {
+            window: 0,
+            ..BufferLimits::default()
+        };
+
+        assert_eq!(agree(limits).err(), Some(Incompatibility::Window));
+    }
+
+    #[test]
+    fn frames_too_small_for_any_item_are_rejected() {
+        let limits = BufferLimits {
+            max_frame_size: MIN_FRAME_SIZE - 1,
+            ..BufferLimits::default()
+        };
+
+        assert_eq!(
+            agree(limits).err(),
+            Some(Incompatibility::FrameSize(MIN_FRAME_SIZE as u64 - 1))
+        );
+        assert!(agree(BufferLimits {
+            max_frame_size: MIN_FRAME_SIZE,
+            ..BufferLimits::default()
+        })
+        .is_ok());
+    }
+}
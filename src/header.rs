@@ -0,0 +1,173 @@
+use super::{ContextHandle, CONTROL};
+
+/// Layout of the context handle that prefixes every frame.
+///
+/// Each end lists the layouts it supports in its handshake, and both then
+/// use the newest layout listed by both. In frames written to a context, the
+/// handle is followed by a byte of flags noting how the payload was
+/// compressed and whether the item continues in the next frame, while
+/// control frames carry no flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Header {
+    /// A 4-byte big-endian handle, limiting handles to 32 bits.
+    Fixed = 1,
+    /// A LEB128 varint of the handle plus one, so that both the root context
+    /// and the control handle take up a single byte.
+    Varint = 2,
+}
+
+impl Header {
+    /// Supported layouts, from oldest to newest.
+    pub(super) const SUPPORTED: [Header; 2] = [Header::Fixed, Header::Varint];
+
+    /// Returns the largest handle that can be allocated to a context, as
+    /// the next one up encodes `CONTROL`.
+    pub(super) fn max_handle(self) -> u64 {
+        match self {
+            Header::Fixed => u64::from(u32::MAX) - 1,
+            Header::Varint => CONTROL.0 - 1,
+        }
+    }
+
+    /// Returns the encoded length of `handle`.
+    pub(super) fn len(self, handle: ContextHandle) -> usize {
+        match self {
+            Header::Fixed => 4,
+            Header::Varint => {
+                let bits = 64 - handle.0.wrapping_add(1).leading_zeros() as usize;
+                bits.div_ceil(7).max(1)
+            }
+        }
+    }
+
+    /// Appends `handle` to `buffer`.
+    pub(super) fn encode(self, handle: ContextHandle, buffer: &mut Vec<u8>) {
+        match self {
+            Header::Fixed => buffer.extend_from_slice(&(handle.0 as u32).to_be_bytes()),
+            Header::Varint => {
+                let mut value = handle.0.wrapping_add(1);
+
+                while value >= 0x80 {
+                    buffer.push(value as u8 | 0x80);
+                    value >>= 7;
+                }
+
+                buffer.push(value as u8);
+            }
+        }
+    }
+
+    /// Reads the handle at the start of `data`, returning it along with its
+    /// encoded length, or `None` if `data` doesn't start with a valid handle.
+    pub(super) fn decode(self, data: &[u8]) -> Option<(ContextHandle, usize)> {
+        match self {
+            Header::Fixed => {
+                let mut id = [0; 4];
+                id.copy_from_slice(data.get(..4)?);
+
+                let handle = match u32::from_be_bytes(id) {
+                    u32::MAX => CONTROL,
+                    id => ContextHandle(id.into()),
+                };
+
+                Some((handle, 4))
+            }
+            Header::Varint => {
+                let mut value = 0u64;
+
+                for (index, byte) in data.iter().take(10).enumerate() {
+                    if index == 9 && *byte > 1 {
+                        return None;
+                    }
+
+                    value |= u64::from(byte & 0x7f) << (7 * index);
+
+                    if byte & 0x80 == 0 {
+                        return Some((ContextHandle(value.wrapping_sub(1)), index + 1));
+                    }
+                }
+
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(header: Header, handle: ContextHandle) -> Vec<u8> {
+        let mut buffer = vec![];
+        header.encode(handle, &mut buffer);
+
+        assert_eq!(buffer.len(), header.len(handle));
+        assert_eq!(header.decode(&buffer), Some((handle, buffer.len())));
+
+        buffer
+    }
+
+    #[test]
+    fn varints_round_trip() {
+        let cases = [
+            (0, 1),
+            (CONTROL.0, 1),
+            (126, 1),
+            (127, 2),
+            (128, 2),
+            (u64::from(u32::MAX), 5),
+            (u64::MAX - 1, 10),
+        ];
+
+        for (handle, len) in cases {
+            assert_eq!(round_trip(Header::Varint, ContextHandle(handle)).len(), len);
+        }
+    }
+
+    #[test]
+    fn varints_overflowing_64_bits_are_rejected() {
+        let mut data = vec![0xff; 9];
+        data.push(0x01);
+        assert_eq!(
+            Header::Varint.decode(&data),
+            Some((ContextHandle(u64::MAX - 1), 10))
+        );
+
+        *data.last_mut().unwrap() = 0x02;
+        assert_eq!(Header::Varint.decode(&data), None);
+
+        *data.last_mut().unwrap() = 0x81;
+        data.push(0x00);
+        assert_eq!(Header::Varint.decode(&data), None);
+    }
+
+    #[test]
+    fn truncated_handles_are_rejected() {
+        assert_eq!(Header::Varint.decode(&[]), None);
+        assert_eq!(Header::Varint.decode(&[0x80, 0x80]), None);
+        assert_eq!(Header::Fixed.decode(&[0, 0, 1]), None);
+    }
+
+    #[test]
+    fn fixed_handles_round_trip() {
+        for handle in [0, 1, 2, Header::Fixed.max_handle()] {
+            let buffer = round_trip(Header::Fixed, ContextHandle(handle));
+            assert_eq!(buffer, (handle as u32).to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn fixed_headers_map_the_largest_id_to_control() {
+        let buffer = round_trip(Header::Fixed, CONTROL);
+        assert_eq!(buffer, u32::MAX.to_be_bytes());
+    }
+
+    #[test]
+    fn handles_past_the_maximum_encode_control() {
+        for header in Header::SUPPORTED {
+            let mut buffer = vec![];
+            header.encode(ContextHandle(header.max_handle() + 1), &mut buffer);
+            assert_eq!(header.decode(&buffer), Some((CONTROL, buffer.len())));
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use futures::ready;
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// A future resolving once the duration it was created with has elapsed.
+pub type Delay = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Source of the delays driving keepalive pings and the expiry of buffers,
+/// supplied by the user so that both work with any executor.
+///
+/// Implemented for every closure returning a future from a duration, such as
+/// the sleep function of the runtime the connection runs on.
+pub trait Timer: Send + Sync {
+    /// Returns a future resolving once `duration` has elapsed.
+    fn delay(&self, duration: Duration) -> Delay;
+}
+
+impl<F, D> Timer for F
+where
+    F: Fn(Duration) -> D + Send + Sync,
+    D: Future<Output = ()> + Send + 'static,
+{
+    fn delay(&self, duration: Duration) -> Delay {
+        Box::pin(self(duration))
+    }
+}
+
+/// How often an end pings its peer, and how long it waits for each pong.
+#[derive(Clone)]
+pub(super) struct Keepalive {
+    pub(super) timer: Arc<dyn Timer>,
+    pub(super) interval: Duration,
+    pub(super) timeout: Duration,
+}
+
+/// What the router should do once a keepalive delay elapsed.
+pub(super) enum Tick {
+    /// Send a ping with the given sequence number.
+    Ping(u64),
+    /// The peer failed to answer the last ping in time.
+    Expired,
+}
+
+/// Pings the peer whenever the connection has gone `interval` without one,
+/// expecting a pong within `timeout`.
+pub(super) struct Pinger {
+    keepalive: Keepalive,
+    /// Pending until the handshake completes, and after the pinger stopped.
+    delay: Option<Delay>,
+    /// Sequence number and send time of the ping awaiting a pong.
+    awaiting: Option<(u64, Instant)>,
+    next: u64,
+    round_trip_time: Arc<Mutex<Option<Duration>>>,
+}
+
+impl Pinger {
+    pub(super) fn new(keepalive: Keepalive, round_trip_time: Arc<Mutex<Option<Duration>>>) -> Self {
+        Pinger {
+            keepalive,
+            delay: None,
+            awaiting: None,
+            next: 0,
+            round_trip_time,
+        }
+    }
+
+    /// Waits for the first interval to elapse once the handshake completed.
+    pub(super) fn start(&mut self) {
+        self.delay = Some(self.keepalive.timer.delay(self.keepalive.interval));
+    }
+
+    /// Stops pinging, once the connection is shutting down or has failed.
+    pub(super) fn stop(&mut self) {
+        self.delay = None;
+    }
+
+    pub(super) fn is_running(&self) -> bool {
+        self.delay.is_some()
+    }
+
+    pub(super) fn poll_tick(&mut self, cx: &mut Context) -> Poll<Tick> {
+        let delay = match &mut self.delay {
+            Some(delay) => delay,
+            None => return Poll::Pending,
+        };
+
+        ready!(delay.as_mut().poll(cx));
+
+        if self.awaiting.is_some() {
+            self.delay = None;
+            return Poll::Ready(Tick::Expired);
+        }
+
+        let sequence = self.next;
+        self.next += 1;
+        self.awaiting = Some((sequence, Instant::now()));
+        self.delay = Some(self.keepalive.timer.delay(self.keepalive.timeout));
+
+        Poll::Ready(Tick::Ping(sequence))
+    }
+
+    /// Records the pong answering the ping with the given sequence number,
+    /// waiting for another interval before the next ping.
+    pub(super) fn pong(&mut self, sequence: u64) {
+        match self.awaiting {
+            Some((expected, sent)) if expected == sequence && self.delay.is_some() => {
+                *self.round_trip_time.lock().unwrap() = Some(sent.elapsed());
+                self.awaiting = None;
+                self.start();
+            }
+            _ => {}
+        }
+    }
+}
@@ -1,9 +1,14 @@
-use bincode::{deserialize as from_slice, serialize as to_vec};
+use bincode::{serialize_into, serialized_size};
+use bytes::Bytes;
 use core_error::Error;
 use futures::{
-    channel::mpsc::{channel as mpsc, unbounded, Sender as MpscSender, UnboundedSender},
-    lock::Mutex,
+    channel::{
+        mpsc::{unbounded, UnboundedSender},
+        oneshot,
+    },
+    io::{AsyncRead, AsyncWrite},
     ready,
+    stream::iter,
     task::{Spawn, SpawnError, SpawnExt},
     FutureExt as _, Sink, Stream, StreamExt, TryStream, TryStreamExt,
 };
@@ -14,36 +19,97 @@ use protocol::{
     CloneContext, ContextReference, Contextualize, Dispatch, Finalize, FinalizeImmediate, Fork,
     Future as _, FutureExt, Join, Notify, Read, ReferenceContext, ShareContext, Write,
 };
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
     borrow::BorrowMut,
-    collections::HashMap,
-    convert::TryInto,
+    collections::HashSet,
     future::Future,
+    io,
     marker::PhantomData,
     pin::Pin,
     sync::{
-        atomic::{AtomicU32, Ordering},
-        Arc,
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex as StdMutex, Weak,
     },
-    task::{Context, Poll},
+    task::{Context, Poll, Waker},
+    time::Duration,
 };
 use thiserror::Error;
 
-#[derive(Hash, PartialEq, Eq, Clone, Copy)]
-pub struct ContextHandle(u32);
+mod builder;
+mod checksum;
+mod compression;
+mod encryption;
+pub mod format;
+mod fragment;
+mod framing;
+mod handshake;
+mod header;
+mod keepalive;
+pub mod memory;
+mod router;
+mod scheduler;
+
+pub use builder::TransportBuilder;
+use checksum::CHECKSUM_LEN;
+pub use compression::Compression;
+use compression::{compress, decompress, UNCOMPRESSED};
+#[cfg(feature = "encryption")]
+pub use encryption::PreSharedKey;
+use encryption::{transcript, Sealer, TAG_LEN};
+pub use format::{Bincode, Format};
+use fragment::Fragments;
+pub use framing::{FramedRead, FramedWrite, FramingError};
+use handshake::{Agreement, Handshake};
+pub use handshake::{Fingerprint, Incompatibility};
+use header::Header;
+use keepalive::Pinger;
+pub use keepalive::{Delay, Timer};
+pub use router::BufferLimits;
+use router::{route, Channels, Incoming, Storage};
+use scheduler::{Drain, Scheduler};
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
+pub struct ContextHandle(u64);
 
 #[derive(Debug, Error, Clone)]
 #[bounds(where E: Error + 'static)]
 pub enum SerdeReadError<E> {
     #[error("error in underlying stream: {0}")]
     Stream(E),
-    #[error("serde error")]
-    Serde,
+    #[error("serde error in {handle:?} decoding a {length} byte frame: {source}")]
+    Serde {
+        handle: ContextHandle,
+        length: usize,
+        source: Arc<dyn Error + Send + Sync>,
+    },
     #[error("received insufficient buffer")]
     Insufficient,
     #[error("stream completed early")]
     Terminated,
+    #[error("peer exceeded the buffer limits for unjoined contexts")]
+    BufferExceeded,
+    #[error("peer exceeded the receive window of {0:?}")]
+    WindowExceeded(ContextHandle),
+    #[error("incompatible peer: {0}")]
+    Incompatible(Incompatibility),
+    #[error("peer sent a {0} byte frame, exceeding the maximum frame size")]
+    Oversized(usize),
+    #[error("framing error: {0}")]
+    Framing(FramingError),
+    #[error("failed to decompress a {length} byte frame in {handle:?}")]
+    Decompression {
+        handle: ContextHandle,
+        length: usize,
+    },
+    #[error("received a frame that failed authentication")]
+    Authentication,
+    #[error("received a corrupted frame claiming to be for {handle:?}")]
+    Corrupt { handle: Option<ContextHandle> },
+    #[error("peer sent an item to {0:?} exceeding the maximum message size")]
+    OversizedMessage(ContextHandle),
+    #[error("peer stopped answering keepalive pings")]
+    Timeout,
 }
 
 #[derive(Debug, Error, Clone)]
@@ -51,156 +117,916 @@ pub enum SerdeReadError<E> {
 pub enum SerdeWriteError<E> {
     #[error("error in underlying sink: {0}")]
     Sink(#[source] E),
-    #[error("serde error")]
-    Serde,
+    #[error("serde error in {handle:?}: {source}")]
+    Serde {
+        handle: ContextHandle,
+        source: Arc<dyn Error + Send + Sync>,
+    },
+    #[error(
+        "{length} byte item written to {handle:?} exceeds the maximum message size of the peer"
+    )]
+    Oversized {
+        handle: ContextHandle,
+        length: usize,
+    },
+    #[error("connection closed")]
+    Closed,
+}
+
+/// The reason a context could not be forked or joined.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum HandleError {
+    #[error("every context handle available to this end is in use")]
+    Exhausted,
+    #[error("{0:?} has already been joined")]
+    Duplicate(ContextHandle),
 }
 
 #[derive(Debug, Error)]
-#[bounds(where E: Error + 'static)]
-pub enum WithSpawnError<E> {
+#[bounds(where E: Error + 'static, R: Error + 'static)]
+pub enum WithSpawnError<E, R> {
     #[error("error in underlying protocol: {0}")]
     Protocol(#[source] E),
     #[error("spawn error: {0}")]
     Spawn(#[source] SpawnError),
+    #[error("incompatible peer: {0}")]
+    Incompatible(#[source] Incompatibility),
+    #[error("connection failed before the handshake completed: {0}")]
+    Connection(#[source] R),
+}
+
+/// Handle reserved for control frames exchanged between the two ends of a
+/// connection. Never allocated to a context.
+const CONTROL: ContextHandle = ContextHandle(u64::MAX);
+
+#[derive(Serialize, Deserialize)]
+enum Control {
+    /// The sender has dropped every local clone of the context with the given
+    /// handle and will not write to it again.
+    Close(u64),
+    /// The sender is shutting down the connection and will neither read nor
+    /// write any further frames.
+    Goodbye,
+    /// The sender has read the given number of frames from the context with
+    /// the given handle, so as many more may be written to it.
+    Credit(u64, u32),
+    /// The sender is checking that the peer is still there, expecting a
+    /// `Pong` with the same sequence number.
+    Ping(u64),
+    /// Answers the `Ping` with the given sequence number.
+    Pong(u64),
+}
+
+enum Command {
+    Open(ContextHandle, Sender<Bytes>, Weak<ContextState>),
+    Close(ContextHandle),
+    Consumed(ContextHandle),
+    Shutdown(oneshot::Sender<()>),
+}
+
+/// Closes the connection shared by a set of contexts.
+///
+/// Shutting down flushes frames that were already written, notifies the peer,
+/// closes the underlying sink and stops the tasks spawned for the connection.
+/// Any further reads or writes on its contexts fail.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    commands: UnboundedSender<Command>,
+}
+
+impl ShutdownHandle {
+    /// Begins shutting down the connection, the returned future resolves once
+    /// every task spawned for it has terminated.
+    pub fn shutdown(&self) -> Shutdown {
+        let (sender, receiver) = oneshot::channel();
+        let _ = self.commands.unbounded_send(Command::Shutdown(sender));
+        Shutdown(receiver)
+    }
+}
+
+/// Future returned by `ShutdownHandle::shutdown`.
+pub struct Shutdown(oneshot::Receiver<()>);
+
+impl Future for Shutdown {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let _ = ready!(Pin::new(&mut self.0).poll(cx));
+        Poll::Ready(())
+    }
 }
 
-pub struct Transport<S: Spawn, StreamError, SinkError, P> {
+/// Send credit for a single context, granted by the peer as it reads the
+/// frames written to that context.
+struct Credit {
+    available: u32,
+    released: bool,
+    wakers: Vec<Waker>,
+}
+
+/// Shared by every clone of a `Transport` for a given handle, tracks the send
+/// credit granted by the peer and notifies the channel registrar once the
+/// last of them is dropped.
+struct ContextState {
     id: ContextHandle,
-    next_index: Arc<AtomicU32>,
+    commands: UnboundedSender<Command>,
+    credit: StdMutex<Credit>,
+}
+
+impl ContextState {
+    fn new(id: ContextHandle, commands: UnboundedSender<Command>, window: u32) -> Self {
+        ContextState {
+            id,
+            commands,
+            credit: StdMutex::new(Credit {
+                available: window,
+                released: false,
+                wakers: vec![],
+            }),
+        }
+    }
+
+    /// Starts over with the credit of a whole window, once the peer has
+    /// announced its window in the handshake.
+    fn reset(&self, window: u32) {
+        self.credit.lock().unwrap().available = window;
+    }
+
+    /// Takes a single frame worth of credit, waiting for the peer to grant
+    /// more if none is available.
+    fn poll_acquire(&self, cx: &mut Context) -> Poll<()> {
+        let mut credit = self.credit.lock().unwrap();
+
+        if credit.released {
+            return Poll::Ready(());
+        }
+
+        if credit.available > 0 {
+            credit.available -= 1;
+            return Poll::Ready(());
+        }
+
+        if !credit
+            .wakers
+            .iter()
+            .any(|waker| waker.will_wake(cx.waker()))
+        {
+            credit.wakers.push(cx.waker().clone());
+        }
+
+        Poll::Pending
+    }
+
+    fn grant(&self, frames: u32) {
+        let mut credit = self.credit.lock().unwrap();
+
+        credit.available = credit.available.saturating_add(frames);
+
+        for waker in credit.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+
+    /// Stops limiting writes to this context, either because the peer will
+    /// not read from it anymore or because the connection has ended.
+    fn release(&self) {
+        let mut credit = self.credit.lock().unwrap();
+
+        credit.released = true;
+
+        for waker in credit.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for ContextState {
+    fn drop(&mut self) {
+        let _ = self.commands.unbounded_send(Command::Close(self.id));
+    }
+}
+
+/// An error shared by every context of a connection. Only the first error
+/// set is retained.
+struct Latch<E> {
+    error: StdMutex<Option<E>>,
+}
+
+impl<E: Clone> Latch<E> {
+    fn new() -> Self {
+        Latch {
+            error: StdMutex::new(None),
+        }
+    }
+
+    fn get(&self) -> Option<E> {
+        self.error.lock().unwrap().clone()
+    }
+
+    fn set(&self, error: E) {
+        self.error.lock().unwrap().get_or_insert(error);
+    }
+}
+
+struct HandleState {
+    first: u64,
+    next: u64,
+    live: HashSet<ContextHandle>,
+    free: Vec<ContextHandle>,
+}
+
+/// The handles of a connection in use at the local end.
+///
+/// A handle stays in use until both ends have closed its context and the
+/// local end's `Control::Close` has been handed to the sink, so frames for a
+/// new context reusing it can never reach the peer ahead of that close.
+struct Handles {
+    state: StdMutex<HandleState>,
+}
+
+impl Handles {
+    fn new(first: u64) -> Self {
+        let mut live = HashSet::new();
+        live.insert(ContextHandle(0));
+
+        Handles {
+            state: StdMutex::new(HandleState {
+                first,
+                next: first,
+                live,
+                free: vec![],
+            }),
+        }
+    }
+
+    /// Allocates a handle for a context forked locally, reusing one that was
+    /// released if possible. Handles above `max` can't be encoded.
+    fn allocate(&self, max: u64) -> Result<ContextHandle, HandleError> {
+        let mut state = self.state.lock().unwrap();
+
+        let handle = match state.free.pop() {
+            Some(handle) => handle,
+            None if state.next <= max => {
+                let handle = ContextHandle(state.next);
+                state.next = state.next.saturating_add(2);
+                handle
+            }
+            None => return Err(HandleError::Exhausted),
+        };
+
+        state.live.insert(handle);
+
+        Ok(handle)
+    }
+
+    /// Marks a handle forked by the peer as joined locally.
+    fn claim(&self, handle: ContextHandle) -> Result<(), HandleError> {
+        if self.state.lock().unwrap().live.insert(handle) {
+            Ok(())
+        } else {
+            Err(HandleError::Duplicate(handle))
+        }
+    }
+
+    /// Whether `handle` belongs to contexts forked locally.
+    fn is_local(&self, handle: ContextHandle) -> bool {
+        let first = self.state.lock().unwrap().first;
+
+        handle.0 >= first && handle.0 % 2 == first % 2
+    }
+
+    fn release(&self, handle: ContextHandle) {
+        let local = self.is_local(handle);
+        let mut state = self.state.lock().unwrap();
+
+        if state.live.remove(&handle) && local {
+            state.free.push(handle);
+        }
+    }
+}
+
+pub struct Transport<S: Spawn, StreamError, SinkError, P, C = Bincode> {
+    id: ContextHandle,
+    handles: Arc<Handles>,
+    discarded: Arc<AtomicU64>,
+    round_trip_time: Arc<StdMutex<Option<Duration>>>,
     spawner: S,
-    receiver: Receiver<Vec<u8>>,
-    sender: MpscSender<Vec<u8>>,
-    sink_error: Receiver<SerdeWriteError<SinkError>>,
-    stream_error: Receiver<SerdeReadError<StreamError>>,
-    new_channel_sender: UnboundedSender<(ContextHandle, Sender<Vec<u8>>)>,
-    _marker: PhantomData<P>,
+    receiver: Receiver<Bytes>,
+    outbound: Arc<Scheduler>,
+    sink_error: Arc<Latch<SerdeWriteError<Arc<SinkError>>>>,
+    stream_error: Arc<Latch<SerdeReadError<Arc<StreamError>>>>,
+    commands: UnboundedSender<Command>,
+    context: Arc<ContextState>,
+    reserved: bool,
+    /// Receive window of the peer, which new contexts start out with as
+    /// credit.
+    window: u32,
+    /// Number of received frames queued for each joined context.
+    capacity: usize,
+    header: Header,
+    max_frame_size: usize,
+    /// Largest item the peer accepts once reassembled.
+    max_message_size: usize,
+    /// Largest item accepted from the peer, which also bounds the size
+    /// frames may decompress to.
+    receive_limit: usize,
+    /// Largest payload written in a single frame before an item is split
+    /// into fragments.
+    fragment_size: usize,
+    compression: Option<Compression>,
+    compression_threshold: usize,
+    checksums: bool,
+    _marker: PhantomData<(P, C)>,
 }
 
-impl<S: Spawn + Clone, StreamError, SinkError, P> Transport<S, StreamError, SinkError, P> {
-    fn next_id(&self) -> Self {
-        let id = ContextHandle(self.next_index.fetch_add(2, Ordering::SeqCst));
+impl<S: Spawn, StreamError, SinkError, P, C> Transport<S, StreamError, SinkError, P, C> {
+    /// Returns the number of frames that arrived for contexts which had
+    /// already been closed and were therefore dropped.
+    pub fn discarded_frames(&self) -> u64 {
+        self.discarded.load(Ordering::Relaxed)
+    }
 
-        let (sender, receiver) = chan(1);
-        let _ = self.new_channel_sender.unbounded_send((id, sender));
+    /// Returns the round-trip time measured by the latest keepalive ping
+    /// answered by the peer, or `None` before the first pong arrived or if
+    /// keepalive pings are disabled.
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        *self.round_trip_time.lock().unwrap()
+    }
 
-        Self {
-            id,
-            next_index: self.next_index.clone(),
-            spawner: self.spawner.clone(),
-            receiver,
-            sender: self.sender.clone(),
-            sink_error: self.sink_error.clone(),
-            stream_error: self.stream_error.clone(),
-            new_channel_sender: self.new_channel_sender.clone(),
-            _marker: PhantomData,
+    /// Returns a handle that can be used to close the connection this
+    /// context belongs to.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            commands: self.commands.clone(),
         }
     }
 
+    /// Sets the share of the connection given to this context while several
+    /// contexts are waiting to write: each round, up to `weight` of its
+    /// frames are written before moving on to the next context. Contexts
+    /// start out with a weight of one, and the weight is shared by every
+    /// clone of this context.
+    pub fn set_priority(&self, weight: u32) {
+        self.outbound.set_weight(self.id, weight);
+    }
+}
+
+impl<S: Spawn + Clone, StreamError, SinkError, P, C> Transport<S, StreamError, SinkError, P, C> {
+    fn next_id(&self) -> Result<Self, HandleError> {
+        let id = self.handles.allocate(self.header.max_handle())?;
+
+        Ok(self.with_id(id))
+    }
+
+    fn join_id(&self, id: ContextHandle) -> Result<Self, HandleError> {
+        self.handles.claim(id)?;
+
+        Ok(self.with_id(id))
+    }
+
     fn with_id(&self, id: ContextHandle) -> Self {
-        let (sender, receiver) = chan(1);
-        let _ = self.new_channel_sender.unbounded_send((id, sender));
+        let (sender, receiver) = chan(self.capacity);
+        let context = Arc::new(ContextState::new(id, self.commands.clone(), self.window));
+        let _ = self
+            .commands
+            .unbounded_send(Command::Open(id, sender, Arc::downgrade(&context)));
 
         Self {
             id,
-            next_index: self.next_index.clone(),
+            handles: self.handles.clone(),
+            discarded: self.discarded.clone(),
+            round_trip_time: self.round_trip_time.clone(),
             spawner: self.spawner.clone(),
             receiver,
-            sender: self.sender.clone(),
+            outbound: self.outbound.clone(),
             sink_error: self.sink_error.clone(),
             stream_error: self.stream_error.clone(),
-            new_channel_sender: self.new_channel_sender.clone(),
+            commands: self.commands.clone(),
+            context,
+            reserved: false,
+            window: self.window,
+            capacity: self.capacity,
+            header: self.header,
+            max_frame_size: self.max_frame_size,
+            max_message_size: self.max_message_size,
+            receive_limit: self.receive_limit,
+            fragment_size: self.fragment_size,
+            compression: self.compression,
+            compression_threshold: self.compression_threshold,
+            checksums: self.checksums,
             _marker: PhantomData,
         }
     }
 }
 
-impl<S: Spawn + Clone, StreamError, SinkError, P> Clone
-    for Transport<S, StreamError, SinkError, P>
+impl<S: Spawn + Clone, StreamError, SinkError, P, C> Clone
+    for Transport<S, StreamError, SinkError, P, C>
 {
     fn clone(&self) -> Self {
         Self {
-            id: self.id.clone(),
-            next_index: self.next_index.clone(),
+            id: self.id,
+            handles: self.handles.clone(),
+            discarded: self.discarded.clone(),
+            round_trip_time: self.round_trip_time.clone(),
             spawner: self.spawner.clone(),
             receiver: self.receiver.clone(),
             stream_error: self.stream_error.clone(),
-            sender: self.sender.clone(),
+            outbound: self.outbound.clone(),
             sink_error: self.sink_error.clone(),
-            new_channel_sender: self.new_channel_sender.clone(),
+            commands: self.commands.clone(),
+            context: self.context.clone(),
+            reserved: false,
+            window: self.window,
+            capacity: self.capacity,
+            header: self.header,
+            max_frame_size: self.max_frame_size,
+            max_message_size: self.max_message_size,
+            receive_limit: self.receive_limit,
+            fragment_size: self.fragment_size,
+            compression: self.compression,
+            compression_threshold: self.compression_threshold,
+            checksums: self.checksums,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<S: Spawn, T, U, P, C> Unpin for Transport<S, T, U, P, C> {}
+
+/// Binary data written to or read from a context as is, bypassing the
+/// serialization format.
+///
+/// Reading a `Payload` hands out the received frame itself rather than a copy
+/// of it, so large binary blobs reach the protocol type without being copied
+/// after they were read from the stream. Items read as any other type are
+/// deserialized into owned values, see `Borrowed` for borrowing from the frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Payload(pub Bytes);
+
+/// An item read from a context and kept in the frame it arrived in, to be
+/// deserialized later by borrowing from that frame.
+///
+/// Deserializing types such as `&str` or `&[u8]`, or structs holding them,
+/// from a `Borrowed` refers to the received frame rather than copying out of
+/// it. The item is written as usual on the other end, and errors in
+/// deserializing it surface from `deserialize` rather than the read.
+pub struct Borrowed<C = Bincode> {
+    data: Bytes,
+    _marker: PhantomData<fn() -> C>,
+}
+
+impl<C: Format> Borrowed<C> {
+    /// Deserializes the item, borrowing from the frame wherever the type
+    /// allows.
+    pub fn deserialize<'de, T: Deserialize<'de>>(&'de self) -> Result<T, C::Error> {
+        C::deserialize(&self.data)
+    }
+}
+
+impl<C> Clone for Borrowed<C> {
+    fn clone(&self) -> Self {
+        Borrowed {
+            data: self.data.clone(),
             _marker: PhantomData,
         }
     }
 }
 
-impl<S: Spawn, T, U, P> Unpin for Transport<S, T, U, P> {}
+impl<C> std::fmt::Debug for Borrowed<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_tuple("Borrowed").field(&self.data).finish()
+    }
+}
 
-impl<S: Spawn, I: DeserializeOwned, T, U, P> Read<I> for Transport<S, T, U, P> {
-    type Error = SerdeReadError<T>;
+impl<S: Spawn, T, U, P, C> Transport<S, T, U, P, C> {
+    fn poll_frame(&mut self, cx: &mut Context) -> Poll<Result<Bytes, SerdeReadError<Arc<T>>>> {
+        if let Some(error) = self.stream_error.get() {
+            return Poll::Ready(Err(error));
+        }
+
+        let data = match ready!(Pin::new(&mut self.receiver).poll_next(cx)) {
+            Some(data) => data,
+            None => {
+                return Poll::Ready(Err(self
+                    .stream_error
+                    .get()
+                    .unwrap_or(SerdeReadError::Terminated)))
+            }
+        };
+
+        let _ = self.commands.unbounded_send(Command::Consumed(self.id));
+
+        Poll::Ready(match data.first() {
+            Some(&UNCOMPRESSED) => Ok(data.slice(1..)),
+            Some(&flag) => decompress(flag, &data[1..], self.receive_limit)
+                .map(Bytes::from)
+                .ok_or(SerdeReadError::Decompression {
+                    handle: self.id,
+                    length: data.len() - 1,
+                }),
+            None => Err(SerdeReadError::Insufficient),
+        })
+    }
+}
+
+impl<S: Spawn, I: DeserializeOwned, T, U, P, C: Format> Read<I> for Transport<S, T, U, P, C> {
+    type Error = SerdeReadError<Arc<T>>;
 
     fn read(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<I, Self::Error>> {
         let this = &mut *self;
 
-        if let Poll::Ready(Some(error)) = Pin::new(&mut this.stream_error).poll_next(cx) {
+        let data = ready!(this.poll_frame(cx))?;
+
+        Poll::Ready(C::deserialize(&data).map_err(|e| SerdeReadError::Serde {
+            handle: this.id,
+            length: data.len(),
+            source: Arc::new(e),
+        }))
+    }
+}
+
+impl<S: Spawn, T, U, P, C> Read<Payload> for Transport<S, T, U, P, C> {
+    type Error = SerdeReadError<Arc<T>>;
+
+    fn read(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Payload, Self::Error>> {
+        Poll::Ready(Ok(Payload(ready!(self.poll_frame(cx))?)))
+    }
+}
+
+impl<S: Spawn, T, U, P, C: Format> Read<Borrowed<C>> for Transport<S, T, U, P, C> {
+    type Error = SerdeReadError<Arc<T>>;
+
+    fn read(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Borrowed<C>, Self::Error>> {
+        Poll::Ready(Ok(Borrowed {
+            data: ready!(self.poll_frame(cx))?,
+            _marker: PhantomData,
+        }))
+    }
+}
+
+impl<S: Spawn, T, U, P, C> Transport<S, T, U, P, C> {
+    fn closed(&self) -> SerdeWriteError<Arc<U>> {
+        self.sink_error.get().unwrap_or(SerdeWriteError::Closed)
+    }
+
+    /// Waits for the handshake with the peer to complete. Should the
+    /// connection end before then, fails with the error that ended it.
+    #[allow(clippy::type_complexity)]
+    fn poll_handshake<E>(
+        &mut self,
+        handshake: &mut Option<oneshot::Receiver<Result<Agreement, Incompatibility>>>,
+        cx: &mut Context,
+    ) -> Poll<Result<(), WithSpawnError<E, SerdeReadError<Arc<T>>>>> {
+        if let Some(receiver) = handshake {
+            let result = ready!(Pin::new(receiver).poll(cx));
+            *handshake = None;
+
+            let agreement = match result {
+                Ok(agreement) => agreement.map_err(WithSpawnError::Incompatible)?,
+                Err(_) => {
+                    return Poll::Ready(Err(WithSpawnError::Connection(
+                        self.stream_error
+                            .get()
+                            .unwrap_or(SerdeReadError::Terminated),
+                    )))
+                }
+            };
+
+            self.header = agreement.header;
+            self.window = agreement.window;
+            self.context.reset(agreement.window);
+            self.max_frame_size = agreement.max_frame_size;
+            self.max_message_size = agreement.max_message_size;
+            self.compression = agreement.compression;
+            self.checksums = agreement.checksums;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    /// Returns a buffer holding the header of a frame for this context, with
+    /// room for `capacity` bytes of payload.
+    fn frame(&self, capacity: usize) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.header.len(self.id) + 1 + capacity);
+        self.header.encode(self.id, &mut data);
+        data.push(UNCOMPRESSED);
+        data
+    }
+
+    /// Queues a frame started by `frame`, compressing its payload if
+    /// worthwhile and splitting it into fragments that fit the maximum frame
+    /// size of the peer. Items are checked against the maximum message size
+    /// of the peer before compression, as they must still fit once
+    /// decompressed.
+    ///
+    /// Rejected items keep the credit reserved for them, as the peer never
+    /// receives them to grant it back.
+    fn start_frame(&mut self, data: Vec<u8>) -> Result<(), SerdeWriteError<Arc<U>>> {
+        let offset = self.header.len(self.id) + 1;
+
+        if data.len() - offset > self.max_message_size {
+            return Err(SerdeWriteError::Oversized {
+                handle: self.id,
+                length: data.len() - offset,
+            });
+        }
+
+        self.reserved = false;
+
+        let data = compress(self.compression, self.compression_threshold, data, offset);
+        // The limit of the peer is at least `MIN_FRAME_SIZE`, which always
+        // leaves room for some payload.
+        let size = self.fragment_size.min(self.max_frame_size - offset);
+
+        self.outbound
+            .push(self.id, Fragments::new(data, offset, size, self.checksums))
+            .map_err(|_| self.closed())
+    }
+
+    fn poll_ready_frame(&mut self, cx: &mut Context) -> Poll<Result<(), SerdeWriteError<Arc<U>>>> {
+        if let Some(error) = self.sink_error.get() {
+            return Poll::Ready(Err(error));
+        }
+
+        if !self.reserved {
+            ready!(self.context.poll_acquire(cx));
+            self.reserved = true;
+        }
+
+        Poll::Ready(ready!(self.outbound.poll_ready(self.id, cx)).map_err(|_| self.closed()))
+    }
+
+    fn poll_flush_frames(&mut self, cx: &mut Context) -> Poll<Result<(), SerdeWriteError<Arc<U>>>> {
+        if let Some(error) = self.sink_error.get() {
             return Poll::Ready(Err(error));
         }
 
-        let data =
-            ready!(Pin::new(&mut this.receiver).poll_next(cx)).ok_or(SerdeReadError::Terminated)?;
+        ready!(self.outbound.poll_flush(self.id, cx)).map_err(|_| self.closed())?;
 
-        Poll::Ready(from_slice(&data[4..]).map_err(|_| SerdeReadError::Serde))
+        Poll::Ready(self.sink_error.get().map_or(Ok(()), Err))
     }
 }
 
-impl<S: Spawn, I: Serialize, T, U, P> Write<I> for Transport<S, T, U, P> {
-    type Error = SerdeWriteError<U>;
+impl<S: Spawn, I: Serialize, T, U, P, C: Format> Write<I> for Transport<S, T, U, P, C> {
+    type Error = SerdeWriteError<Arc<U>>;
 
     fn write(mut self: Pin<&mut Self>, item: I) -> Result<(), Self::Error> {
         let this = &mut *self;
 
-        let mut data = this.id.0.to_be_bytes().as_ref().to_owned();
-        data.append(&mut to_vec(&item).map_err(|_| SerdeWriteError::Serde)?);
-
-        Pin::new(&mut this.sender).start_send(data).unwrap();
+        let mut data = this.frame(C::size_hint(&item));
+        C::serialize_into(&mut data, &item).map_err(|e| SerdeWriteError::Serde {
+            handle: this.id,
+            source: Arc::new(e),
+        })?;
 
-        Ok(())
+        this.start_frame(data)
     }
 
     fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        let this = &mut *self;
+        self.poll_ready_frame(cx)
+    }
 
-        if let Poll::Ready(Some(error)) = Pin::new(&mut this.sink_error).poll_next(cx) {
-            return Poll::Ready(Err(error));
-        }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush_frames(cx)
+    }
+}
 
-        ready!(Pin::new(&mut this.sender).poll_ready(cx)).unwrap();
+impl<S: Spawn, T, U, P, C> Write<Payload> for Transport<S, T, U, P, C> {
+    type Error = SerdeWriteError<Arc<U>>;
 
-        Poll::Ready(Ok(()))
+    fn write(mut self: Pin<&mut Self>, item: Payload) -> Result<(), Self::Error> {
+        let mut data = self.frame(item.0.len());
+        data.extend_from_slice(&item.0);
+
+        self.start_frame(data)
+    }
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.poll_ready_frame(cx)
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
-        let this = &mut *self;
+        self.poll_flush_frames(cx)
+    }
+}
 
-        if let Poll::Ready(Some(error)) = Pin::new(&mut this.sink_error).poll_next(cx) {
-            return Poll::Ready(Err(error));
-        }
+/// Wraps the errors of a stream of frames supplied by the user.
+fn frames<T: TryStream>(
+    stream: T,
+) -> impl TryStream<Ok = Bytes, Error = SerdeReadError<Arc<T::Error>>>
+where
+    T::Ok: Into<Bytes>,
+{
+    stream
+        .map_ok(Into::into)
+        .map_err(|e| SerdeReadError::Stream(Arc::new(e)))
+}
 
-        ready!(Pin::new(&mut this.sender).poll_flush(cx)).unwrap();
+/// Returns the largest frame that may arrive over the underlying stream when
+/// the frames written to it hold up to `max_frame_size` bytes, allowing for
+/// a checksum and, if `encrypted`, the tag added by encryption.
+fn max_wire_frame_size(max_frame_size: usize, encrypted: bool) -> usize {
+    let overhead = if encrypted { TAG_LEN } else { 0 } + CHECKSUM_LEN;
+    max_frame_size.saturating_add(overhead)
+}
 
-        Poll::Ready(Ok(()))
+type Initializer = Box<dyn FnOnce(Handshake) -> Result<(), SpawnError> + Send>;
+
+/// Sets up the root context of a new connection as configured by `builder`,
+/// returning it along with a closure that sends the given handshake and
+/// spawns the tasks driving the connection, the handshake this end will
+/// send, and a receiver for the outcome of that handshake.
+///
+/// Errors in `stream` are expected to have been wrapped already, so that
+/// adapters like `Frames` can report errors of their own.
+///
+/// Contexts forked locally are numbered from `first_index` in steps of two,
+/// so that the two ends of a connection never allocate the same handle.
+#[allow(clippy::type_complexity)]
+fn connect<T, E, U, S, P, C>(
+    stream: T,
+    sink: U,
+    builder: TransportBuilder<S, C>,
+    first_index: u64,
+) -> (
+    Transport<S, E, U::Error, P, C>,
+    Initializer,
+    Handshake,
+    oneshot::Receiver<Result<Agreement, Incompatibility>>,
+)
+where
+    T: TryStream<Ok = Bytes, Error = SerdeReadError<Arc<E>>> + Unpin + Send + 'static,
+    U: Sink<Vec<u8>> + Send + 'static,
+    S: Spawn + Clone + Send + 'static,
+    E: Send + Sync + 'static,
+    U::Error: Send + Sync,
+    C: Format,
+{
+    let TransportBuilder {
+        spawner,
+        limits,
+        outbound_capacity,
+        fingerprint,
+        compression,
+        compression_threshold,
+        key,
+        checksums,
+        fragment_size,
+        keepalive,
+        timer,
+        ..
+    } = builder;
+
+    let mut handshake = Handshake::new(C::NAME, &limits);
+    if let Some(fingerprint) = fingerprint {
+        handshake.set_fingerprint(fingerprint);
+    }
+    if let Some(compression) = compression {
+        handshake.set_compression(compression);
+    }
+
+    if checksums {
+        handshake.set_checksums();
+    }
+
+    let mut sealer = key.as_ref().map(Sealer::new);
+    if let Some(sealer) = &sealer {
+        handshake.set_nonce_prefix(sealer.prefix());
     }
+
+    let receive_limit = limits.max_message_size;
+    let window = limits.window;
+    // Routing never waits on the channel of a context as long as it can hold
+    // every frame the peer may have outstanding.
+    let capacity = window as usize;
+
+    let (b_sender, receiver) = chan(capacity);
+    let outbound = Arc::new(Scheduler::new(outbound_capacity));
+
+    let sink_error = Arc::new(Latch::new());
+    let sink_error_latch = sink_error.clone();
+    let stream_error = Arc::new(Latch::new());
+    let stream_error_latch = stream_error.clone();
+
+    let (commands, command_receiver) = unbounded();
+
+    let discarded = Arc::new(AtomicU64::new(0));
+    let round_trip_time = Arc::new(StdMutex::new(None));
+    let timer = timer.or_else(|| keepalive.as_ref().map(|keepalive| keepalive.timer.clone()));
+    let pinger = keepalive.map(|keepalive| Pinger::new(keepalive, round_trip_time.clone()));
+
+    let context = Arc::new(ContextState::new(
+        ContextHandle(0),
+        commands.clone(),
+        window,
+    ));
+
+    let handles = Arc::new(Handles::new(first_index));
+
+    let mut channels = Channels::new(limits, discarded.clone(), handles.clone());
+
+    channels.insert(
+        ContextHandle(0),
+        Storage::Channel(b_sender),
+        Arc::downgrade(&context),
+    );
+
+    let (sink_closed_sender, sink_closed) = oneshot::channel();
+    let (agreed, agreement) = oneshot::channel();
+    let (peer_hello, received_hello) = oneshot::channel::<Bytes>();
+
+    let control_outbound = outbound.clone();
+    let drain = Drain(outbound.clone());
+
+    let s = spawner.clone();
+
+    let initializer = move |handshake: Handshake| {
+        let hello = handshake.encode();
+
+        let written = hello.clone();
+        let mut received_hello = Some(received_hello);
+
+        spawner.spawn(async move {
+            let mut outbound = drain;
+            let frames = outbound.by_ref().map(|frame| match &mut sealer {
+                Some(sealer) => {
+                    // Nothing is written before the handshake of the peer
+                    // was agreed upon, so it has always arrived by the time
+                    // the first frame is sealed.
+                    if let Some(Ok(Some(read))) =
+                        received_hello.take().map(|mut hello| hello.try_recv())
+                    {
+                        sealer.bind(transcript(&written, &read));
+                    }
+                    sealer.seal(frame)
+                }
+                None => frame,
+            });
+            if let Err(e) = iter(Some(hello)).chain(frames).map(Ok).forward(sink).await {
+                sink_error_latch.set(SerdeWriteError::Sink(Arc::new(e)));
+            }
+            let _ = sink_closed_sender.send(());
+        })?;
+
+        spawner.spawn(route(
+            Incoming::new(stream),
+            command_receiver,
+            sink_closed,
+            handshake,
+            key,
+            pinger,
+            timer,
+            agreed,
+            peer_hello,
+            control_outbound,
+            channels,
+            stream_error_latch,
+        ))
+    };
+
+    let transport = Transport {
+        handles,
+        discarded,
+        round_trip_time,
+        spawner: s,
+        outbound,
+        receiver,
+        sink_error,
+        id: ContextHandle(0),
+        _marker: PhantomData,
+        stream_error,
+        context,
+        reserved: false,
+        window,
+        capacity,
+        header: Header::Fixed,
+        max_frame_size: usize::MAX,
+        max_message_size: usize::MAX,
+        receive_limit,
+        fragment_size,
+        compression: None,
+        compression_threshold,
+        checksums: false,
+        commands,
+    };
+
+    (transport, Box::new(initializer), handshake, agreement)
 }
 
 pub struct Coalesce<
-    T: TryStream<Ok = Vec<u8>>,
+    T: TryStream,
     U: Sink<Vec<u8>>,
     S: Spawn,
-    P: protocol::Coalesce<Transport<S, T::Error, U::Error, P>>,
+    P: protocol::Coalesce<Transport<S, T::Error, U::Error, P, C>>,
+    C = Bincode,
 > where
     P::Future: Unpin,
 {
     fut: P::Future,
-    transport: Transport<S, T::Error, U::Error, P>,
-    initializer: Option<Box<dyn FnOnce() -> Result<(), SpawnError> + Send>>,
+    transport: Transport<S, T::Error, U::Error, P, C>,
+    initializer: Option<Initializer>,
+    handshake: Handshake,
+    agreement: Option<oneshot::Receiver<Result<Agreement, Incompatibility>>>,
 }
 
 enum UnravelState<T, U> {
@@ -209,41 +1035,50 @@ enum UnravelState<T, U> {
 }
 
 pub struct Unravel<
-    T: TryStream<Ok = Vec<u8>>,
+    T: TryStream,
     U: Sink<Vec<u8>>,
     S: Spawn,
-    P: protocol::Unravel<Transport<S, T::Error, U::Error, P>>,
+    P: protocol::Unravel<Transport<S, T::Error, U::Error, P, C>>,
+    C = Bincode,
 > where
     P::Target: Unpin,
     P::Finalize: Unpin,
 {
     fut: UnravelState<P::Target, P::Finalize>,
-    transport: Transport<S, T::Error, U::Error, P>,
-    initializer: Option<Box<dyn FnOnce() -> Result<(), SpawnError> + Send>>,
+    transport: Transport<S, T::Error, U::Error, P, C>,
+    initializer: Option<Initializer>,
+    handshake: Handshake,
+    agreement: Option<oneshot::Receiver<Result<Agreement, Incompatibility>>>,
 }
 
 impl<
-        T: TryStream<Ok = Vec<u8>>,
+        T: TryStream,
         U: Sink<Vec<u8>>,
         S: Spawn,
-        P: protocol::Unravel<Transport<S, T::Error, U::Error, P>>,
-    > Future for Unravel<T, U, S, P>
+        P: protocol::Unravel<Transport<S, T::Error, U::Error, P, C>>,
+        C,
+    > Future for Unravel<T, U, S, P, C>
 where
     P::Target: Unpin,
     P::Finalize: Unpin,
 {
     type Output = Result<
         (),
-        WithSpawnError<<P::Target as protocol::Future<Transport<S, T::Error, U::Error, P>>>::Error>,
+        WithSpawnError<
+            <P::Target as protocol::Future<Transport<S, T::Error, U::Error, P, C>>>::Error,
+            SerdeReadError<Arc<T::Error>>,
+        >,
     >;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         let this = &mut *self;
 
         if let Some(initializer) = this.initializer.take() {
-            (initializer)().map_err(WithSpawnError::Spawn)?;
+            (initializer)(this.handshake.clone()).map_err(WithSpawnError::Spawn)?;
         }
 
+        ready!(this.transport.poll_handshake(&mut this.agreement, cx))?;
+
         loop {
             match &mut this.fut {
                 UnravelState::Target(future) => {
@@ -262,26 +1097,32 @@ where
 }
 
 impl<
-        T: TryStream<Ok = Vec<u8>>,
+        T: TryStream,
         U: Sink<Vec<u8>>,
         S: Spawn,
-        P: protocol::Coalesce<Transport<S, T::Error, U::Error, P>>,
-    > Future for Coalesce<T, U, S, P>
+        P: protocol::Coalesce<Transport<S, T::Error, U::Error, P, C>>,
+        C,
+    > Future for Coalesce<T, U, S, P, C>
 where
     P::Future: Unpin,
 {
     type Output = Result<
         P,
-        WithSpawnError<<P::Future as protocol::Future<Transport<S, T::Error, U::Error, P>>>::Error>,
+        WithSpawnError<
+            <P::Future as protocol::Future<Transport<S, T::Error, U::Error, P, C>>>::Error,
+            SerdeReadError<Arc<T::Error>>,
+        >,
     >;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         let this = &mut *self;
 
         if let Some(initializer) = this.initializer.take() {
-            (initializer)().map_err(WithSpawnError::Spawn)?;
+            (initializer)(this.handshake.clone()).map_err(WithSpawnError::Spawn)?;
         }
 
+        ready!(this.transport.poll_handshake(&mut this.agreement, cx))?;
+
         Pin::new(&mut this.fut)
             .poll(cx, &mut this.transport)
             .map_err(WithSpawnError::Protocol)
@@ -289,248 +1130,144 @@ where
 }
 
 impl<
-        T: Unpin + TryStream<Ok = Vec<u8>> + Send + 'static,
+        T: Unpin + TryStream + Send + 'static,
         U: Sink<Vec<u8>> + Send + 'static,
         S: Clone + Send + Spawn + 'static,
         P: protocol::Coalesce<Transport<S, T::Error, U::Error, P>>,
     > Coalesce<T, U, S, P>
 where
     P::Future: Unpin,
-    T::Error: Send,
-    U::Error: Send,
+    T::Ok: Into<Bytes>,
+    T::Error: Send + Sync,
+    U::Error: Send + Sync,
 {
     pub fn new(stream: T, sink: U, spawner: S) -> Self {
-        let (b_sender, receiver) = chan(1);
-        let (sender, b_receiver) = mpsc(1);
-
-        let (sink_error_sender, sink_error) = chan(1);
-        let (stream_error_sender, stream_error) = chan(1);
-
-        let (new_channel_sender, mut new_channels) = unbounded();
-
-        let mut channels = HashMap::<ContextHandle, Storage<Vec<u8>>>::new();
-
-        channels.insert(ContextHandle(0), Storage::Channel(b_sender));
-
-        let channels = Arc::new(Mutex::new(channels));
-
-        let channels_handle = channels.clone();
-
-        let s = spawner.clone();
-
-        let initializer = move || {
-            spawner.spawn(async move {
-                if let Err(e) = b_receiver.map(Ok).forward(sink).await {
-                    let _ = sink_error_sender.send(SerdeWriteError::Sink(e));
-                }
-            })?;
-
-            spawner.spawn(async move {
-                while let Some((handle, channel)) = new_channels.next().await {
-                    channels_handle
-                        .lock()
-                        .await
-                        .entry(handle)
-                        .or_insert_with(|| Storage::Temporary(vec![]))
-                        .upgrade(channel)
-                        .await;
-                }
-            })?;
-
-            let mut stream = stream.into_stream();
-
-            spawner.spawn(async move {
-                while let Some(data) = stream.next().await {
-                    match data {
-                        Err(e) => {
-                            let _ = stream_error_sender.send(SerdeReadError::Stream(e));
-                            break;
-                        }
-                        Ok(data) => {
-                            let id = data.get(..4);
-                            match id {
-                                Some(id) => {
-                                    let handle =
-                                        ContextHandle(u32::from_be_bytes(id.try_into().unwrap()));
-                                    channels
-                                        .lock()
-                                        .await
-                                        .entry(handle)
-                                        .or_insert_with(|| Storage::Temporary(vec![]))
-                                        .send(data)
-                                        .await;
-                                }
-                                None => {
-                                    let _ = stream_error_sender.send(SerdeReadError::Insufficient);
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                }
-            })?;
-
-            Ok(())
-        };
-
-        Coalesce {
-            transport: Transport {
-                next_index: Arc::new(AtomicU32::new(2)),
-                spawner: s,
-                sender,
-                receiver,
-                sink_error,
-                id: ContextHandle(0),
-                _marker: PhantomData,
-                stream_error,
-                new_channel_sender,
-            },
-            initializer: Some(Box::new(initializer)),
-            fut: P::coalesce(),
-        }
+        TransportBuilder::new(spawner).coalesce(stream, sink)
     }
 }
 
-enum Storage<T> {
-    Temporary(Vec<T>),
-    Channel(Sender<T>),
+impl<
+        T: Unpin + TryStream + Send + 'static,
+        U: Sink<Vec<u8>> + Send + 'static,
+        S: Clone + Send + Spawn + 'static,
+        P: protocol::Coalesce<Transport<S, T::Error, U::Error, P, C>>,
+        C: Format,
+    > Coalesce<T, U, S, P, C>
+where
+    P::Future: Unpin,
+    T::Ok: Into<Bytes>,
+    T::Error: Send + Sync,
+    U::Error: Send + Sync,
+{
+    /// Returns the number of frames that arrived for already closed contexts
+    /// and were discarded.
+    pub fn discarded_frames(&self) -> u64 {
+        self.transport.discarded_frames()
+    }
+
+    /// Returns a handle that can be used to close the connection.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.transport.shutdown_handle()
+    }
 }
 
-impl<T> Storage<T> {
-    async fn send(&mut self, item: T) {
-        match self {
-            Storage::Temporary(data) => data.push(item),
-            Storage::Channel(sender) => sender.send(item).await,
-        }
+impl<
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+        S: Clone + Send + Spawn + 'static,
+        P: protocol::Coalesce<Transport<S, io::Error, io::Error, P>>,
+    > Coalesce<FramedRead<R>, FramedWrite<W>, S, P>
+where
+    P::Future: Unpin,
+{
+    /// Like `new`, but reading and writing length-delimited frames over a
+    /// byte stream such as a socket.
+    pub fn from_io(reader: R, writer: W, spawner: S) -> Self {
+        TransportBuilder::new(spawner).coalesce_io(reader, writer)
     }
+}
 
-    async fn upgrade(&mut self, channel: Sender<T>) {
-        match self {
-            Storage::Temporary(data) => {
-                for item in data.drain(..) {
-                    channel.send(item).await;
-                }
-                *self = Storage::Channel(channel);
-            }
-            Storage::Channel(_) => panic!("attempted redundant channel upgrade"),
-        }
+impl Control {
+    fn encode(&self, header: Header) -> Vec<u8> {
+        let size = serialized_size(self).expect("control frames are always serializable");
+        let mut data = Vec::with_capacity(header.len(CONTROL) + size as usize);
+        header.encode(CONTROL, &mut data);
+        serialize_into(&mut data, self).expect("control frames are always serializable");
+        data
     }
 }
 
 impl<
-        T: TryStream<Ok = Vec<u8>> + Unpin + Send + 'static,
+        T: TryStream + Unpin + Send + 'static,
         U: Sink<Vec<u8>> + Send + 'static,
         S: Clone + Send + Spawn + 'static,
         P: protocol::Unravel<Transport<S, T::Error, U::Error, P>>,
     > Unravel<T, U, S, P>
 where
     P::Target: Unpin,
-    T::Error: Send,
-    U::Error: Send,
+    T::Ok: Into<Bytes>,
+    T::Error: Send + Sync,
+    U::Error: Send + Sync,
     P::Finalize: Unpin,
 {
     pub fn new(stream: T, sink: U, spawner: S, item: P) -> Self {
-        let (b_sender, receiver) = chan(1);
-        let (sender, b_receiver) = mpsc(1);
-
-        let (sink_error_sender, sink_error) = chan(1);
-        let (stream_error_sender, stream_error) = chan(1);
-
-        let (new_channel_sender, mut new_channels) = unbounded();
-
-        let mut channels = HashMap::<ContextHandle, Storage<Vec<u8>>>::new();
-
-        channels.insert(ContextHandle(0), Storage::Channel(b_sender));
-
-        let channels = Arc::new(Mutex::new(channels));
-
-        let channels_handle = channels.clone();
-
-        let s = spawner.clone();
+        TransportBuilder::new(spawner).unravel(stream, sink, item)
+    }
+}
 
-        let initializer = move || {
-            spawner.spawn(async move {
-                if let Err(e) = b_receiver.map(|item| Ok(item)).forward(sink).await {
-                    let _ = sink_error_sender.send(SerdeWriteError::Sink(e));
-                }
-            })?;
-
-            spawner.spawn(async move {
-                while let Some((handle, channel)) = new_channels.next().await {
-                    channels_handle
-                        .lock()
-                        .await
-                        .entry(handle)
-                        .or_insert_with(|| Storage::Temporary(vec![]))
-                        .upgrade(channel)
-                        .await;
-                }
-            })?;
-
-            let mut stream = stream.into_stream();
-
-            spawner.spawn(async move {
-                while let Some(data) = stream.next().await {
-                    match data {
-                        Err(e) => {
-                            let _ = stream_error_sender.send(SerdeReadError::Stream(e));
-                            break;
-                        }
-                        Ok(data) => {
-                            let id = data.get(..4);
-                            match id {
-                                Some(id) => {
-                                    let handle =
-                                        ContextHandle(u32::from_be_bytes(id.try_into().unwrap()));
-                                    channels
-                                        .lock()
-                                        .await
-                                        .entry(handle)
-                                        .or_insert_with(|| Storage::Temporary(vec![]))
-                                        .send(data)
-                                        .await;
-                                }
-                                None => {
-                                    let _ = stream_error_sender.send(SerdeReadError::Insufficient);
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                }
-            })?;
+impl<
+        T: TryStream + Unpin + Send + 'static,
+        U: Sink<Vec<u8>> + Send + 'static,
+        S: Clone + Send + Spawn + 'static,
+        P: protocol::Unravel<Transport<S, T::Error, U::Error, P, C>>,
+        C: Format,
+    > Unravel<T, U, S, P, C>
+where
+    P::Target: Unpin,
+    T::Ok: Into<Bytes>,
+    T::Error: Send + Sync,
+    U::Error: Send + Sync,
+    P::Finalize: Unpin,
+{
+    /// Returns the number of frames that arrived for already closed contexts
+    /// and were discarded.
+    pub fn discarded_frames(&self) -> u64 {
+        self.transport.discarded_frames()
+    }
 
-            Ok(())
-        };
+    /// Returns a handle that can be used to close the connection.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.transport.shutdown_handle()
+    }
+}
 
-        Unravel {
-            transport: Transport {
-                next_index: Arc::new(AtomicU32::new(1)),
-                spawner: s,
-                sender,
-                receiver,
-                sink_error,
-                id: ContextHandle(0),
-                _marker: PhantomData,
-                stream_error,
-                new_channel_sender,
-            },
-            initializer: Some(Box::new(initializer)),
-            fut: UnravelState::Target(item.unravel()),
-        }
+impl<
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+        S: Clone + Send + Spawn + 'static,
+        P: protocol::Unravel<Transport<S, io::Error, io::Error, P>>,
+    > Unravel<FramedRead<R>, FramedWrite<W>, S, P>
+where
+    P::Target: Unpin,
+    P::Finalize: Unpin,
+{
+    /// Like `new`, but reading and writing length-delimited frames over a
+    /// byte stream such as a socket.
+    pub fn from_io(reader: R, writer: W, spawner: S, item: P) -> Self {
+        TransportBuilder::new(spawner).unravel_io(reader, writer, item)
     }
 }
 
-impl<S: Spawn, T, U, P: protocol::Coalesce<Self>, M> Dispatch<P> for Transport<S, T, U, M> {
+impl<S: Spawn, T, U, P: protocol::Coalesce<Self>, M, C> Dispatch<P> for Transport<S, T, U, M, C> {
     type Handle = ();
 }
 
-impl<S: Spawn, T, U, P, M> Dispatch<Notification<P>> for Transport<S, T, U, M> {
+impl<S: Spawn, T, U, P, M, C> Dispatch<Notification<P>> for Transport<S, T, U, M, C> {
     type Handle = ();
 }
 
-impl<S: Spawn, T, U, P: protocol::Coalesce<Self> + protocol::Unravel<Self>, M> Fork<P>
-    for Transport<S, T, U, M>
+impl<S: Spawn, T, U, P: protocol::Coalesce<Self> + protocol::Unravel<Self>, M, C> Fork<P>
+    for Transport<S, T, U, M, C>
 where
     <P as protocol::Unravel<Self>>::Target: Unpin,
 {
@@ -543,7 +1280,7 @@ where
     }
 }
 
-impl<S: Spawn, T, U, P: protocol::Coalesce<Self>, M> Join<P> for Transport<S, T, U, M> {
+impl<S: Spawn, T, U, P: protocol::Coalesce<Self>, M, C> Join<P> for Transport<S, T, U, M, C> {
     type Future = <P as protocol::Coalesce<Self>>::Future;
 
     fn join(&mut self, _: ()) -> Self::Future {
@@ -553,7 +1290,8 @@ impl<S: Spawn, T, U, P: protocol::Coalesce<Self>, M> Join<P> for Transport<S, T,
 
 pub struct Notification<P>(P);
 
-impl<S: Spawn, T, U, P: protocol::Coalesce<Self>, M> Join<Notification<P>> for Transport<S, T, U, M>
+impl<S: Spawn, T, U, P: protocol::Coalesce<Self>, M, C> Join<Notification<P>>
+    for Transport<S, T, U, M, C>
 where
     <P as protocol::Coalesce<Self>>::Future: Unpin,
 {
@@ -564,7 +1302,8 @@ where
     }
 }
 
-impl<S: Spawn, T, U, P: protocol::Unravel<Self>, M> Fork<Notification<P>> for Transport<S, T, U, M>
+impl<S: Spawn, T, U, P: protocol::Unravel<Self>, M, C> Fork<Notification<P>>
+    for Transport<S, T, U, M, C>
 where
     <P as protocol::Unravel<Self>>::Target: Unpin,
 {
@@ -577,8 +1316,8 @@ where
     }
 }
 
-impl<S: Spawn, T, U, P: protocol::Unravel<Self> + protocol::Coalesce<Self> + Unpin, M> Notify<P>
-    for Transport<S, T, U, M>
+impl<S: Spawn, T, U, P: protocol::Unravel<Self> + protocol::Coalesce<Self> + Unpin, M, C> Notify<P>
+    for Transport<S, T, U, M, C>
 where
     <P as protocol::Unravel<Self>>::Target: Unpin,
     <P as protocol::Coalesce<Self>>::Future: Unpin,
@@ -596,13 +1335,13 @@ where
     }
 }
 
-pub struct Contextualized<S: Spawn, T, U, F, P> {
+pub struct Contextualized<S: Spawn, T, U, F, P, C = Bincode> {
     fut: F,
-    transport: Transport<S, T, U, P>,
+    transport: Transport<S, T, U, P, C>,
 }
 
-impl<S: Spawn + Unpin, T, U, F: Unpin + protocol::Future<Transport<S, T, U, P>>, P> Future
-    for Contextualized<S, T, U, F, P>
+impl<S: Spawn + Unpin, T, U, F: Unpin + protocol::Future<Transport<S, T, U, P, C>>, P, C> Future
+    for Contextualized<S, T, U, F, P, C>
 {
     type Output = Result<F::Ok, F::Error>;
 
@@ -613,46 +1352,48 @@ impl<S: Spawn + Unpin, T, U, F: Unpin + protocol::Future<Transport<S, T, U, P>>,
     }
 }
 
-impl<S: Spawn, T, U, P> Contextualize for Transport<S, T, U, P> {
-    type Handle = u32;
+impl<S: Spawn, T, U, P, C> Contextualize for Transport<S, T, U, P, C> {
+    type Handle = u64;
 }
 
-impl<S: Spawn + Clone + Unpin, T, U, P> CloneContext for Transport<S, T, U, P> {
-    type Context = Transport<S, T, U, P>;
-    type ForkOutput = Ready<(Transport<S, T, U, P>, u32)>;
-    type JoinOutput = Ready<Transport<S, T, U, P>>;
+impl<S: Spawn + Clone + Unpin, T, U, P, C> CloneContext for Transport<S, T, U, P, C> {
+    type Context = Transport<S, T, U, P, C>;
+    type ForkOutput = Ready<(Transport<S, T, U, P, C>, u64), HandleError>;
+    type JoinOutput = Ready<Transport<S, T, U, P, C>, HandleError>;
 
     fn fork_owned(&mut self) -> Self::ForkOutput {
-        let tport = self.next_id();
-        let id = tport.id.0;
-        ok((tport, id))
+        ready(self.next_id().map(|tport| {
+            let id = tport.id.0;
+            (tport, id)
+        }))
     }
 
     fn join_owned(&mut self, id: Self::Handle) -> Self::JoinOutput {
-        ok(self.with_id(ContextHandle(id)))
+        ready(self.join_id(ContextHandle(id)))
     }
 }
 
-impl<S: Spawn + Clone + Unpin, T, U, P> ShareContext for Transport<S, T, U, P> {
-    type Context = Transport<S, T, U, P>;
-    type ForkOutput = Ready<(Transport<S, T, U, P>, u32)>;
-    type JoinOutput = Ready<Transport<S, T, U, P>>;
+impl<S: Spawn + Clone + Unpin, T, U, P, C> ShareContext for Transport<S, T, U, P, C> {
+    type Context = Transport<S, T, U, P, C>;
+    type ForkOutput = Ready<(Transport<S, T, U, P, C>, u64), HandleError>;
+    type JoinOutput = Ready<Transport<S, T, U, P, C>, HandleError>;
 
     fn fork_shared(&mut self) -> Self::ForkOutput {
-        let tport = self.next_id();
-        let id = tport.id.0;
-        ok((tport, id))
+        ready(self.next_id().map(|tport| {
+            let id = tport.id.0;
+            (tport, id)
+        }))
     }
 
     fn join_shared(&mut self, id: Self::Handle) -> Self::JoinOutput {
-        ok(self.with_id(ContextHandle(id)))
+        ready(self.join_id(ContextHandle(id)))
     }
 }
 
-impl<S: Spawn, T, U, P> ContextReference<Transport<S, T, U, P>> for Transport<S, T, U, P> {
-    type Target = Transport<S, T, U, P>;
+impl<S: Spawn, T, U, P, C> ContextReference<Transport<S, T, U, P, C>> for Transport<S, T, U, P, C> {
+    type Target = Transport<S, T, U, P, C>;
 
-    fn with<'a, 'b: 'a, R: BorrowMut<Transport<S, T, U, P>> + 'b>(
+    fn with<'a, 'b: 'a, R: BorrowMut<Transport<S, T, U, P, C>> + 'b>(
         &'a mut self,
         _: R,
     ) -> &'a mut Self::Target {
@@ -660,19 +1401,20 @@ impl<S: Spawn, T, U, P> ContextReference<Transport<S, T, U, P>> for Transport<S,
     }
 }
 
-impl<S: Spawn + Clone + Unpin, T, U, P> ReferenceContext for Transport<S, T, U, P> {
-    type Context = Transport<S, T, U, P>;
-    type ForkOutput = Ready<(Transport<S, T, U, P>, u32)>;
-    type JoinOutput = Ready<Transport<S, T, U, P>>;
+impl<S: Spawn + Clone + Unpin, T, U, P, C> ReferenceContext for Transport<S, T, U, P, C> {
+    type Context = Transport<S, T, U, P, C>;
+    type ForkOutput = Ready<(Transport<S, T, U, P, C>, u64), HandleError>;
+    type JoinOutput = Ready<Transport<S, T, U, P, C>, HandleError>;
 
     fn fork_ref(&mut self) -> Self::ForkOutput {
-        let tport = self.next_id();
-        let id = tport.id.0;
-        ok((tport, id))
+        ready(self.next_id().map(|tport| {
+            let id = tport.id.0;
+            (tport, id)
+        }))
     }
 
     fn join_ref(&mut self, id: Self::Handle) -> Self::JoinOutput {
-        ok(self.with_id(ContextHandle(id)))
+        ready(self.join_id(ContextHandle(id)))
     }
 }
 
@@ -682,7 +1424,8 @@ impl<
         T: Send + Sync + 'static,
         U: Send + Sync + 'static,
         P: Send + 'static,
-    > Finalize<F> for Transport<S, T, U, P>
+        C: Send + 'static,
+    > Finalize<F> for Transport<S, T, U, P, C>
 {
     type Target = Self;
     type Output = Ready<(), SpawnError>;
@@ -706,7 +1449,8 @@ impl<
         T: Send + Sync + 'static,
         U: Send + Sync + 'static,
         P: Send + 'static,
-    > FinalizeImmediate<F> for Transport<S, T, U, P>
+        C: Send + 'static,
+    > FinalizeImmediate<F> for Transport<S, T, U, P, C>
 {
     type Target = Self;
     type Error = SpawnError;
@@ -728,19 +1472,21 @@ pub struct ProtocolMveTransport;
 #[cfg(feature = "vessels")]
 mod vessels {
     use super::{Coalesce, ProtocolMveTransport, Transport, Unravel};
+    use bytes::Bytes;
     use erasure_traits::{FramedTransportCoalesce, FramedTransportUnravel};
     use futures::{task::Spawn, Sink, TryStream};
 
     impl<
-            U: TryStream<Ok = Vec<u8>>,
+            U: TryStream,
             V: Sink<Vec<u8>>,
             T: protocol::Coalesce<Transport<S, U::Error, V::Error, T>>,
             S: Spawn + Unpin,
         > FramedTransportCoalesce<T, U, V, S> for ProtocolMveTransport
     where
         T::Future: Unpin,
-        U::Error: Send,
-        V::Error: Send,
+        U::Ok: Into<Bytes>,
+        U::Error: Send + Sync,
+        V::Error: Send + Sync,
         U: Send + Unpin + 'static,
         V: Send + 'static,
         S: Clone + Send + 'static,
@@ -753,7 +1499,7 @@ mod vessels {
     }
 
     impl<
-            U: TryStream<Ok = Vec<u8>>,
+            U: TryStream,
             V: Sink<Vec<u8>>,
             T: protocol::Unravel<Transport<S, U::Error, V::Error, T>>,
             S: Spawn + Unpin,
@@ -761,8 +1507,9 @@ mod vessels {
     where
         T::Target: Unpin,
         T::Finalize: Unpin,
-        U::Error: Send,
-        V::Error: Send,
+        U::Ok: Into<Bytes>,
+        U::Error: Send + Sync,
+        V::Error: Send + Sync,
         U: Send + Unpin + 'static,
         V: Send + 'static,
         S: Clone + Send + 'static,
@@ -774,3 +1521,46 @@ mod vessels {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handles_are_exhausted_past_the_maximum() {
+        let handles = Handles::new(1);
+
+        assert_eq!(handles.allocate(5), Ok(ContextHandle(1)));
+        assert_eq!(handles.allocate(5), Ok(ContextHandle(3)));
+        assert_eq!(handles.allocate(5), Ok(ContextHandle(5)));
+        assert_eq!(handles.allocate(5), Err(HandleError::Exhausted));
+    }
+
+    #[test]
+    fn released_handles_clear_exhaustion() {
+        let handles = Handles::new(2);
+
+        assert_eq!(handles.allocate(4), Ok(ContextHandle(2)));
+        assert_eq!(handles.allocate(4), Ok(ContextHandle(4)));
+        assert_eq!(handles.allocate(4), Err(HandleError::Exhausted));
+
+        handles.release(ContextHandle(2));
+        assert_eq!(handles.allocate(4), Ok(ContextHandle(2)));
+        assert_eq!(handles.allocate(4), Err(HandleError::Exhausted));
+    }
+
+    #[test]
+    fn handles_of_the_peer_are_not_reused() {
+        let handles = Handles::new(1);
+
+        assert_eq!(handles.claim(ContextHandle(2)), Ok(()));
+        assert_eq!(
+            handles.claim(ContextHandle(2)),
+            Err(HandleError::Duplicate(ContextHandle(2)))
+        );
+
+        handles.release(ContextHandle(2));
+        assert_eq!(handles.allocate(1), Ok(ContextHandle(1)));
+        assert_eq!(handles.allocate(1), Err(HandleError::Exhausted));
+    }
+}
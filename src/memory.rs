@@ -0,0 +1,383 @@
+//! In-memory connections for testing protocols without a network.
+//!
+//! Both ends of a connection made by `faulty_pair` share a `Faults` handle
+//! through which the frames in flight can be held back, reordered, corrupted
+//! or dropped altogether. Faults are only ever injected when asked for, so tests using
+//! them remain deterministic.
+
+use super::{Coalesce, Format, Handshake, Transport, TransportBuilder, Unravel, CONTROL};
+use futures::{task::Spawn, Sink, Stream};
+use std::{
+    collections::{HashMap, VecDeque},
+    mem::take,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+use thiserror::Error;
+
+/// The error returned by both ends of a connection once it was dropped by
+/// `Faults::disconnect`.
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[error("in-memory connection dropped")]
+pub struct Disconnected;
+
+#[derive(Default)]
+struct Direction {
+    delivered: VecDeque<Vec<u8>>,
+    held: Vec<Vec<u8>>,
+    handshake: Option<Vec<u8>>,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+impl Direction {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    directions: [Direction; 2],
+    holding: bool,
+    reorder: Option<u64>,
+    corrupt: bool,
+    disconnected: bool,
+}
+
+impl State {
+    /// Returns the key frames in direction `index` are reordered by, or
+    /// `None` for frames no other frame may be moved across.
+    fn key<'a>(&self, index: usize, frame: &'a [u8]) -> Option<&'a [u8]> {
+        let ours = Handshake::decode(self.directions[index].handshake.as_ref()?).ok()?;
+        let theirs = Handshake::decode(self.directions[1 - index].handshake.as_ref()?).ok()?;
+
+        let agreement = ours.agree(&theirs).ok()?;
+
+        // Encrypted frames can't be told apart, and must arrive in order.
+        if agreement.nonce_prefix.is_some() {
+            return None;
+        }
+
+        match agreement.header.decode(frame)? {
+            (CONTROL, _) => None,
+            (_, offset) => Some(&frame[..offset]),
+        }
+    }
+
+    /// Moves the frames held in direction `index` to the receiving end,
+    /// interleaving frames of different contexts if reordering is enabled.
+    fn release(&mut self, index: usize) {
+        let held = take(&mut self.directions[index].held);
+
+        let seed = match &mut self.reorder {
+            Some(seed) => seed,
+            None => {
+                self.directions[index].delivered.extend(held);
+                return;
+            }
+        };
+
+        let mut ordered = Vec::with_capacity(held.len());
+        let mut queues: Vec<VecDeque<Vec<u8>>> = vec![];
+        let mut keys = HashMap::new();
+        let mut state = *seed;
+
+        let mut drain = |queues: &mut Vec<VecDeque<Vec<u8>>>, ordered: &mut Vec<Vec<u8>>| {
+            while !queues.is_empty() {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                let queue = (state % queues.len() as u64) as usize;
+                ordered.push(queues[queue].pop_front().unwrap());
+                if queues[queue].is_empty() {
+                    queues.swap_remove(queue);
+                }
+            }
+        };
+
+        for frame in held {
+            let key = self.key(index, &frame).map(<[u8]>::to_vec);
+
+            match key {
+                Some(key) => {
+                    let queue = *keys.entry(key).or_insert_with(|| {
+                        queues.push(VecDeque::new());
+                        queues.len() - 1
+                    });
+                    queues[queue].push_back(frame);
+                }
+                None => {
+                    drain(&mut queues, &mut ordered);
+                    keys.clear();
+                    ordered.push(frame);
+                }
+            }
+        }
+
+        drain(&mut queues, &mut ordered);
+
+        *self.reorder.as_mut().unwrap() = state;
+        self.directions[index].delivered.extend(ordered);
+    }
+}
+
+/// Injects faults into the connection made by `faulty_pair`.
+#[derive(Clone)]
+pub struct Faults {
+    state: Arc<Mutex<State>>,
+}
+
+impl Faults {
+    /// Holds back frames written by either end until `release` is called.
+    pub fn hold(&self) {
+        self.state.lock().unwrap().holding = true;
+    }
+
+    /// Delivers the frames held back since `hold` was called, and stops
+    /// holding back any more.
+    pub fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        state.holding = false;
+
+        for index in 0..2 {
+            state.release(index);
+            state.directions[index].wake();
+        }
+    }
+
+    /// Delivers held frames written to different contexts in a pseudorandom
+    /// order derived from `seed`. Frames are never reordered within a single
+    /// context, nor moved across frames exchanged by the transport itself.
+    pub fn reorder(&self, seed: u64) {
+        self.state.lock().unwrap().reorder = Some(seed.max(1));
+    }
+
+    /// Flips a bit in the first byte of the next frame either end writes
+    /// after its handshake, which falls within the handle of the context the
+    /// frame is written to.
+    pub fn corrupt(&self) {
+        self.state.lock().unwrap().corrupt = true;
+    }
+
+    /// Drops the connection. Frames in flight are lost, both streams yield
+    /// `Disconnected` and both sinks fail with it.
+    pub fn disconnect(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        state.disconnected = true;
+
+        for direction in &mut state.directions {
+            direction.delivered.clear();
+            direction.held.clear();
+            direction.wake();
+        }
+    }
+}
+
+/// The receiving half of one end of an in-memory connection.
+pub struct LinkStream {
+    state: Arc<Mutex<State>>,
+    index: usize,
+    failed: bool,
+}
+
+impl Stream for LinkStream {
+    type Item = Result<Vec<u8>, Disconnected>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        if self.failed {
+            return Poll::Ready(None);
+        }
+
+        let state = self.state.clone();
+        let mut state = state.lock().unwrap();
+
+        if state.disconnected {
+            self.failed = true;
+            return Poll::Ready(Some(Err(Disconnected)));
+        }
+
+        let direction = &mut state.directions[self.index];
+
+        if let Some(frame) = direction.delivered.pop_front() {
+            Poll::Ready(Some(Ok(frame)))
+        } else if direction.closed && direction.held.is_empty() {
+            Poll::Ready(None)
+        } else {
+            direction.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// The sending half of one end of an in-memory connection.
+pub struct LinkSink {
+    state: Arc<Mutex<State>>,
+    index: usize,
+}
+
+impl LinkSink {
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        let direction = &mut state.directions[self.index];
+        direction.closed = true;
+        direction.wake();
+    }
+}
+
+impl Sink<Vec<u8>> for LinkSink {
+    type Error = Disconnected;
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), Disconnected>> {
+        Poll::Ready(if self.state.lock().unwrap().disconnected {
+            Err(Disconnected)
+        } else {
+            Ok(())
+        })
+    }
+
+    fn start_send(self: Pin<&mut Self>, mut item: Vec<u8>) -> Result<(), Disconnected> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.disconnected {
+            return Err(Disconnected);
+        }
+
+        let holding = state.holding;
+        let corrupt = state.corrupt;
+        let direction = &mut state.directions[self.index];
+
+        if direction.handshake.is_none() {
+            direction.handshake = Some(item.clone());
+        } else if corrupt && !item.is_empty() {
+            item[0] ^= 1;
+            state.corrupt = false;
+        }
+
+        let direction = &mut state.directions[self.index];
+
+        if holding {
+            direction.held.push(item);
+        } else {
+            direction.delivered.push_back(item);
+            direction.wake();
+        }
+
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), Disconnected>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), Disconnected>> {
+        self.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for LinkSink {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// One end of an in-memory connection.
+pub type Link = (LinkStream, LinkSink);
+
+/// Creates two connected ends of an in-memory connection.
+pub fn pair() -> (Link, Link) {
+    let (a, b, _) = faulty_pair();
+    (a, b)
+}
+
+/// Like `pair`, but also returning a handle through which faults can be
+/// injected into the connection.
+pub fn faulty_pair() -> (Link, Link, Faults) {
+    let state = Arc::new(Mutex::new(State::default()));
+
+    let end = |sending: usize| {
+        (
+            LinkStream {
+                state: state.clone(),
+                index: 1 - sending,
+                failed: false,
+            },
+            LinkSink {
+                state: state.clone(),
+                index: sending,
+            },
+        )
+    };
+
+    (
+        end(0),
+        end(1),
+        Faults {
+            state: state.clone(),
+        },
+    )
+}
+
+/// Connects a `Coalesce` and an `Unravel` of the protocol type `P` over an
+/// in-memory connection.
+#[allow(clippy::type_complexity)]
+pub fn connect<P, S>(
+    item: P,
+    spawner: S,
+) -> (
+    Coalesce<LinkStream, LinkSink, S, P>,
+    Unravel<LinkStream, LinkSink, S, P>,
+)
+where
+    S: Spawn + Clone + Send + 'static,
+    P: protocol::Coalesce<Transport<S, Disconnected, Disconnected, P>>
+        + protocol::Unravel<Transport<S, Disconnected, Disconnected, P>>,
+    <P as protocol::Coalesce<Transport<S, Disconnected, Disconnected, P>>>::Future: Unpin,
+    <P as protocol::Unravel<Transport<S, Disconnected, Disconnected, P>>>::Target: Unpin,
+    <P as protocol::Unravel<Transport<S, Disconnected, Disconnected, P>>>::Finalize: Unpin,
+{
+    let (coalesce, unravel, _) = connect_with(
+        item,
+        TransportBuilder::new(spawner.clone()),
+        TransportBuilder::new(spawner),
+    );
+
+    (coalesce, unravel)
+}
+
+/// Like `connect`, but configuring either end with its own builder, and also
+/// returning a handle through which faults can be injected into the
+/// connection.
+#[allow(clippy::type_complexity)]
+pub fn connect_with<P, S, C>(
+    item: P,
+    coalesce: TransportBuilder<S, C>,
+    unravel: TransportBuilder<S, C>,
+) -> (
+    Coalesce<LinkStream, LinkSink, S, P, C>,
+    Unravel<LinkStream, LinkSink, S, P, C>,
+    Faults,
+)
+where
+    S: Spawn + Clone + Send + 'static,
+    C: Format,
+    P: protocol::Coalesce<Transport<S, Disconnected, Disconnected, P, C>>
+        + protocol::Unravel<Transport<S, Disconnected, Disconnected, P, C>>,
+    <P as protocol::Coalesce<Transport<S, Disconnected, Disconnected, P, C>>>::Future: Unpin,
+    <P as protocol::Unravel<Transport<S, Disconnected, Disconnected, P, C>>>::Target: Unpin,
+    <P as protocol::Unravel<Transport<S, Disconnected, Disconnected, P, C>>>::Finalize: Unpin,
+{
+    let ((a_stream, a_sink), (b_stream, b_sink), faults) = faulty_pair();
+
+    (
+        coalesce.coalesce(a_stream, a_sink),
+        unravel.unravel(b_stream, b_sink, item),
+        faults,
+    )
+}
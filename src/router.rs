@@ -0,0 +1,973 @@
+use super::{
+    checksum,
+    encryption::{transcript, Opener, PreSharedKey},
+    fragment::MORE,
+    keepalive::{Delay, Pinger, Tick, Timer},
+    max_wire_frame_size, Agreement, Command, ContextHandle, ContextState, Control, Handles,
+    Handshake, Incompatibility, Latch, Scheduler, SerdeReadError, CONTROL,
+};
+use bincode::deserialize as from_slice;
+use bytes::Bytes;
+use futures::{
+    channel::{mpsc::UnboundedReceiver, oneshot},
+    future::{poll_fn, Fuse, FusedFuture},
+    ready, select,
+    stream::FusedStream,
+    FutureExt, Stream, StreamExt, TryStream,
+};
+use piper::Sender;
+use std::{
+    collections::{HashMap, VecDeque},
+    mem::take,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+/// Where the items received for a context go.
+///
+/// The channel of a joined context holds at least a window of items, and the
+/// peer never has more than a window of frames outstanding, so sending to it
+/// never waits and a context that is slow to read can't hold up the router.
+pub(super) enum Storage<T> {
+    Temporary(Vec<T>),
+    Channel(Sender<T>),
+    Closed,
+}
+
+impl<T> Storage<T> {
+    async fn send(&mut self, item: T) {
+        match self {
+            Storage::Temporary(data) => data.push(item),
+            Storage::Channel(sender) => sender.send(item).await,
+            Storage::Closed => {}
+        }
+    }
+
+    /// Hands buffered frames over to the channel of a newly joined context.
+    ///
+    /// Joining a handle twice is rejected by `Handles::claim` before it gets
+    /// here; should it happen anyway, the redundant channel is dropped so
+    /// reads from it terminate.
+    async fn upgrade(&mut self, channel: Sender<T>) {
+        if let Storage::Temporary(data) = self {
+            for item in data.drain(..) {
+                channel.send(item).await;
+            }
+            *self = Storage::Channel(channel);
+        }
+    }
+}
+
+struct Slot<T> {
+    storage: Storage<T>,
+    context: Weak<ContextState>,
+    buffered_bytes: usize,
+    /// Flags and payload so far of an item whose fragments are still
+    /// arriving, empty between items.
+    partial: Vec<u8>,
+    /// Frames received that have not yet been credited back to the peer.
+    outstanding: u32,
+    /// Frames read locally that have not yet been credited back to the peer.
+    consumed: u32,
+    created: Instant,
+    closed_locally: bool,
+    closed_remotely: bool,
+    /// Whether the local end's `Control::Close` has been queued for sending.
+    close_sent: bool,
+}
+
+impl<T> Slot<T> {
+    fn new(storage: Storage<T>) -> Self {
+        Slot {
+            storage,
+            context: Weak::new(),
+            buffered_bytes: 0,
+            partial: vec![],
+            outstanding: 0,
+            consumed: 0,
+            created: Instant::now(),
+            closed_locally: false,
+            closed_remotely: false,
+            close_sent: false,
+        }
+    }
+
+    fn release(&self) {
+        if let Some(context) = self.context.upgrade() {
+            context.release();
+        }
+    }
+}
+
+/// Limits on data buffered for contexts the peer has written to but that
+/// have not yet been read locally.
+///
+/// Exceeding any of the size limits terminates the connection with
+/// `SerdeReadError::BufferExceeded`, while buffers left unjoined for longer
+/// than `timeout` are silently dropped, crediting their frames back to the
+/// peer. Without a timer, see `TransportBuilder::timer`, buffers are only
+/// dropped as frames arrive or contexts are joined. Frames larger than
+/// `max_frame_size` terminate the connection with `SerdeReadError::Oversized`,
+/// and items reassembled to more than `max_message_size` with
+/// `SerdeReadError::OversizedMessage`.
+#[derive(Debug, Clone)]
+pub struct BufferLimits {
+    /// Number of frames the peer may write to a context before it has to
+    /// wait for them to be read. Announced in the handshake, so the two ends
+    /// of a connection may use different windows.
+    pub window: u32,
+    /// Maximum number of frames buffered for a single context.
+    pub context_frames: usize,
+    /// Maximum number of bytes buffered for a single context.
+    pub context_bytes: usize,
+    /// Maximum number of frames buffered across all contexts.
+    pub total_frames: usize,
+    /// Maximum number of bytes buffered across all contexts.
+    pub total_bytes: usize,
+    /// Time after which the buffer of a context that was never joined is
+    /// dropped.
+    pub timeout: Duration,
+    /// Largest frame accepted from the peer, in bytes. Announced in the
+    /// handshake so that the peer never writes a larger one.
+    pub max_frame_size: usize,
+    /// Largest item accepted from the peer once reassembled from its
+    /// fragments or decompressed, in bytes. Announced in the handshake so
+    /// that the peer never writes a larger one.
+    pub max_message_size: usize,
+}
+
+impl Default for BufferLimits {
+    fn default() -> Self {
+        BufferLimits {
+            window: 32,
+            context_frames: 64,
+            context_bytes: 1 << 20,
+            total_frames: 4096,
+            total_bytes: 16 << 20,
+            timeout: Duration::from_secs(30),
+            max_frame_size: 16 << 20,
+            max_message_size: 16 << 20,
+        }
+    }
+}
+
+fn slot<'a, T>(
+    slots: &'a mut HashMap<ContextHandle, Slot<T>>,
+    orphans: &mut VecDeque<(Instant, ContextHandle)>,
+    handle: ContextHandle,
+) -> &'a mut Slot<T> {
+    slots.entry(handle).or_insert_with(|| {
+        let slot = Slot::new(Storage::Temporary(vec![]));
+        orphans.push_back((slot.created, handle));
+        slot
+    })
+}
+
+/// Routing table for the contexts of a single connection.
+///
+/// A slot is evicted once both ends have closed the corresponding context,
+/// until then frames that arrive after either side closed it are discarded.
+pub(super) struct Channels<T> {
+    slots: HashMap<ContextHandle, Slot<T>>,
+    orphans: VecDeque<(Instant, ContextHandle)>,
+    limits: BufferLimits,
+    buffered_frames: usize,
+    buffered_bytes: usize,
+    /// Number of bytes held by items that are partially reassembled.
+    partial_bytes: usize,
+    /// Credit for the frames of expired buffers, yet to be granted back to
+    /// the peer so that their writers aren't left waiting for it.
+    refunds: Vec<(ContextHandle, u32)>,
+    terminated: bool,
+    discarded: Arc<AtomicU64>,
+    handles: Arc<Handles>,
+}
+
+impl<T: AsRef<[u8]>> Channels<T> {
+    pub(super) fn new(
+        limits: BufferLimits,
+        discarded: Arc<AtomicU64>,
+        handles: Arc<Handles>,
+    ) -> Self {
+        Channels {
+            slots: HashMap::new(),
+            orphans: VecDeque::new(),
+            limits,
+            buffered_frames: 0,
+            buffered_bytes: 0,
+            partial_bytes: 0,
+            refunds: vec![],
+            terminated: false,
+            discarded,
+            handles,
+        }
+    }
+
+    pub(super) fn insert(
+        &mut self,
+        handle: ContextHandle,
+        storage: Storage<T>,
+        context: Weak<ContextState>,
+    ) {
+        let mut slot = Slot::new(storage);
+        slot.context = context;
+        self.slots.insert(handle, slot);
+    }
+
+    fn slot(&mut self, handle: ContextHandle) -> &mut Slot<T> {
+        slot(&mut self.slots, &mut self.orphans, handle)
+    }
+
+    /// Releases the accounting for anything still buffered in the slot for
+    /// `handle`, returning the number of frames that were held.
+    fn unbuffer(&mut self, handle: ContextHandle) -> usize {
+        match self.slots.get_mut(&handle) {
+            Some(Slot {
+                storage: Storage::Temporary(items),
+                buffered_bytes,
+                ..
+            }) => {
+                self.buffered_frames -= items.len();
+                self.buffered_bytes -= *buffered_bytes;
+                *buffered_bytes = 0;
+                items.len()
+            }
+            _ => 0,
+        }
+    }
+
+    /// Evicts the slot for `handle`, returning the number of frames that were
+    /// still buffered in it.
+    fn remove(&mut self, handle: ContextHandle) -> usize {
+        let frames = self.unbuffer(handle);
+
+        if let Some(slot) = self.slots.remove(&handle) {
+            self.partial_bytes -= slot.partial.len();
+        }
+
+        frames
+    }
+
+    /// Whether the orphan entry for `handle` still refers to a buffer that was
+    /// never joined.
+    fn is_orphaned(&self, created: Instant, handle: ContextHandle) -> bool {
+        match self.slots.get(&handle) {
+            Some(slot) => slot.created == created && matches!(slot.storage, Storage::Temporary(_)),
+            None => false,
+        }
+    }
+
+    /// Returns when the oldest buffer of a context that was never joined
+    /// expires, dropping entries for buffers that were joined or evicted.
+    fn deadline(&mut self) -> Option<Instant> {
+        while let Some(&(created, handle)) = self.orphans.front() {
+            if self.is_orphaned(created, handle) {
+                return created.checked_add(self.limits.timeout);
+            }
+
+            self.orphans.pop_front();
+        }
+
+        None
+    }
+
+    /// Drops the buffers of contexts that were never joined within the
+    /// configured timeout.
+    fn expire(&mut self) {
+        let now = Instant::now();
+
+        while let Some(&(created, handle)) = self.orphans.front() {
+            if now.duration_since(created) < self.limits.timeout {
+                break;
+            }
+
+            self.orphans.pop_front();
+
+            if self.is_orphaned(created, handle) {
+                let slot = &self.slots[&handle];
+                // The writer is still waiting for the credit its frames took
+                // unless the peer has closed the context already.
+                if !slot.closed_remotely && slot.outstanding > 0 {
+                    self.refunds.push((handle, slot.outstanding));
+                }
+
+                let frames = self.remove(handle);
+                self.discarded.fetch_add(frames as u64, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Takes the credit owed to the peer for frames dropped from expired
+    /// buffers.
+    fn take_refunds(&mut self) -> Vec<(ContextHandle, u32)> {
+        std::mem::take(&mut self.refunds)
+    }
+
+    async fn send<E>(&mut self, handle: ContextHandle, item: T) -> Result<(), SerdeReadError<E>> {
+        self.expire();
+
+        let slot = slot(&mut self.slots, &mut self.orphans, handle);
+
+        if slot.closed_locally || slot.closed_remotely {
+            self.discarded.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+
+        if slot.outstanding >= self.limits.window {
+            return Err(SerdeReadError::WindowExceeded(handle));
+        }
+
+        slot.outstanding += 1;
+
+        match &mut slot.storage {
+            Storage::Temporary(items) => {
+                let limits = &self.limits;
+                let len = item.as_ref().len();
+
+                if items.len() >= limits.context_frames
+                    || slot.buffered_bytes + len > limits.context_bytes
+                    || self.buffered_frames >= limits.total_frames
+                    || self.buffered_bytes + len > limits.total_bytes
+                {
+                    return Err(SerdeReadError::BufferExceeded);
+                }
+
+                items.push(item);
+                slot.buffered_bytes += len;
+                self.buffered_frames += 1;
+                self.buffered_bytes += len;
+            }
+            storage => storage.send(item).await,
+        }
+
+        Ok(())
+    }
+
+    async fn open(
+        &mut self,
+        handle: ContextHandle,
+        channel: Sender<T>,
+        context: Weak<ContextState>,
+    ) {
+        self.expire();
+
+        self.slot(handle);
+        self.unbuffer(handle);
+
+        let terminated = self.terminated;
+        let slot = self.slot(handle);
+
+        slot.context = context;
+        slot.storage.upgrade(channel).await;
+
+        if slot.closed_remotely || terminated {
+            slot.storage = Storage::Closed;
+            slot.release();
+        }
+    }
+
+    /// Records that a frame was read from the context with the given handle,
+    /// returning the credit to send to the peer once enough has accumulated.
+    fn consume(&mut self, handle: ContextHandle) -> Option<u32> {
+        let threshold = (self.limits.window / 2).max(1);
+        let slot = self.slots.get_mut(&handle)?;
+
+        if slot.closed_locally || slot.closed_remotely {
+            return None;
+        }
+
+        slot.consumed += 1;
+
+        if slot.consumed < threshold {
+            return None;
+        }
+
+        let credit = slot.consumed;
+        slot.consumed = 0;
+        slot.outstanding = slot.outstanding.saturating_sub(credit);
+
+        Some(credit)
+    }
+
+    fn grant(&mut self, handle: ContextHandle, frames: u32) {
+        if let Some(context) = self
+            .slots
+            .get(&handle)
+            .and_then(|slot| slot.context.upgrade())
+        {
+            context.grant(frames);
+        }
+    }
+
+    /// Wakes every writer waiting for credit once the outgoing sink has
+    /// closed, so they observe the failure rather than waiting forever.
+    fn release_writers(&mut self) {
+        for slot in self.slots.values() {
+            slot.release();
+        }
+    }
+
+    /// Closes every context once the incoming stream has ended, so pending
+    /// reads are woken rather than waiting for frames that will never come.
+    fn close_all(&mut self) {
+        self.terminated = true;
+
+        for slot in self.slots.values_mut() {
+            if let Storage::Channel(_) = slot.storage {
+                slot.storage = Storage::Closed;
+            }
+            slot.release();
+        }
+    }
+
+    fn close_local(&mut self, handle: ContextHandle) {
+        let slot = self.slot(handle);
+
+        if slot.closed_remotely {
+            self.remove(handle);
+        } else {
+            slot.closed_locally = true;
+            slot.storage = Storage::Closed;
+        }
+    }
+
+    /// Records that the local end's `Control::Close` for `handle` has been
+    /// queued. Handles forked by the peer can't be reused locally, so they
+    /// are released right away, while those forked locally wait until the
+    /// close has been written.
+    fn queued_close(&mut self, handle: ContextHandle) {
+        if !self.handles.is_local(handle) {
+            self.sent_close(handle);
+        }
+    }
+
+    /// Records that the local end's `Control::Close` for `handle` has been
+    /// sent, releasing the handle for reuse if the peer closed it too.
+    fn sent_close(&mut self, handle: ContextHandle) {
+        match self.slots.get_mut(&handle) {
+            Some(slot) => slot.close_sent = true,
+            None => self.handles.release(handle),
+        }
+    }
+
+    fn close_remote(&mut self, handle: ContextHandle) {
+        let slot = self.slot(handle);
+
+        if slot.closed_locally {
+            let close_sent = slot.close_sent;
+            self.remove(handle);
+            if close_sent {
+                self.handles.release(handle);
+            }
+        } else {
+            slot.closed_remotely = true;
+            slot.release();
+            if let Storage::Channel(_) = slot.storage {
+                slot.storage = Storage::Closed;
+            }
+        }
+    }
+}
+
+impl Channels<Bytes> {
+    /// Collects the fragments of an item written to `handle`, returning the
+    /// item with the flags of its first fragment once the last one arrived.
+    fn reassemble<E>(
+        &mut self,
+        handle: ContextHandle,
+        fragment: Bytes,
+    ) -> Result<Option<Bytes>, SerdeReadError<E>> {
+        let flags = match fragment.first() {
+            Some(&flags) => flags,
+            None => return Ok(Some(fragment)),
+        };
+
+        let limits = &self.limits;
+        let slot = slot(&mut self.slots, &mut self.orphans, handle);
+
+        if slot.partial.is_empty() {
+            if fragment.len() - 1 > limits.max_message_size {
+                return Err(SerdeReadError::OversizedMessage(handle));
+            }
+
+            if flags & MORE == 0 {
+                return Ok(Some(fragment));
+            }
+
+            slot.partial.push(flags & !MORE);
+            self.partial_bytes += 1;
+        }
+
+        let len = fragment.len() - 1;
+
+        if slot.partial.len() - 1 + len > limits.max_message_size {
+            return Err(SerdeReadError::OversizedMessage(handle));
+        }
+
+        if self.partial_bytes + len > limits.total_bytes {
+            return Err(SerdeReadError::BufferExceeded);
+        }
+
+        slot.partial.extend_from_slice(&fragment[1..]);
+        self.partial_bytes += len;
+
+        if flags & MORE != 0 {
+            return Ok(None);
+        }
+
+        let item = take(&mut slot.partial);
+        self.partial_bytes -= item.len();
+
+        Ok(Some(item.into()))
+    }
+}
+
+/// Incoming frames, ending after the first error so that a failed stream is
+/// never polled again.
+pub(super) struct Incoming<T> {
+    stream: Option<T>,
+}
+
+impl<T> Incoming<T> {
+    pub(super) fn new(stream: T) -> Self {
+        Incoming {
+            stream: Some(stream),
+        }
+    }
+
+    fn stop(&mut self) {
+        self.stream = None;
+    }
+}
+
+impl<T: TryStream + Unpin> Stream for Incoming<T> {
+    type Item = Result<T::Ok, T::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let item = match &mut self.stream {
+            Some(stream) => ready!(Pin::new(stream).try_poll_next(cx)),
+            None => return Poll::Ready(None),
+        };
+
+        if let Some(Ok(_)) = item {
+        } else {
+            self.stream = None;
+        }
+
+        Poll::Ready(item)
+    }
+}
+
+impl<T: TryStream + Unpin> FusedStream for Incoming<T> {
+    fn is_terminated(&self) -> bool {
+        self.stream.is_none()
+    }
+}
+
+/// Waits for the oldest buffer of a context that was never joined to
+/// expire, so that it is dropped even while the connection is idle.
+struct Expiry {
+    timer: Arc<dyn Timer>,
+    /// The deadline being waited for, along with the delay elapsing at it.
+    delay: Option<(Instant, Delay)>,
+}
+
+impl Expiry {
+    fn new(timer: Arc<dyn Timer>) -> Self {
+        Expiry { timer, delay: None }
+    }
+
+    /// Resolves once `deadline` has passed, starting a new delay whenever it
+    /// changes.
+    fn poll_deadline(&mut self, deadline: Instant, cx: &mut Context) -> Poll<()> {
+        let delay = match &mut self.delay {
+            Some((at, delay)) if *at == deadline => delay,
+            delay => {
+                let duration = deadline.saturating_duration_since(Instant::now());
+                &mut delay.insert((deadline, self.timer.delay(duration))).1
+            }
+        };
+
+        ready!(delay.as_mut().poll(cx));
+        self.delay = None;
+
+        Poll::Ready(())
+    }
+}
+
+enum Event<E> {
+    Frame(Option<Result<Bytes, E>>),
+    Command(Option<Command>),
+    Tick(Tick),
+    Written(Vec<ContextHandle>),
+    Expiry,
+    SinkClosed,
+    Complete,
+}
+
+/// Hands queued control frames to the scheduler, dropping them if the
+/// outgoing sink has closed.
+fn flush<T: AsRef<[u8]>>(
+    outbound: &Scheduler,
+    pending: &mut VecDeque<Control>,
+    channels: &mut Channels<T>,
+    agreement: Agreement,
+) {
+    for control in pending.drain(..) {
+        let mut frame = control.encode(agreement.header);
+
+        if agreement.checksums {
+            checksum::append(&mut frame);
+        }
+
+        let sent = match control {
+            Control::Close(id) => outbound
+                .push_close(ContextHandle(id), frame)
+                .map(|_| channels.queued_close(ContextHandle(id))),
+            Control::Credit(..) | Control::Ping(_) | Control::Pong(_) => {
+                outbound.push_control(frame)
+            }
+            Control::Goodbye => {
+                outbound.finish(frame);
+                Ok(())
+            }
+        };
+
+        if sent.is_err() {
+            break;
+        }
+    }
+}
+
+/// Routes the frames of a single connection, owning its routing table.
+///
+/// Incoming frames, commands from local contexts, keepalive and expiry
+/// delays and the closing of the outgoing sink are all handled by this one
+/// task, so the table is never shared and routing never waits on a lock.
+/// Control frames are handed to the scheduler rather than written in place,
+/// so a congested sink does not stop incoming frames from being delivered.
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn route<T, E>(
+    mut incoming: Incoming<T>,
+    mut commands: UnboundedReceiver<Command>,
+    sink_closed: oneshot::Receiver<()>,
+    handshake: Handshake,
+    key: Option<PreSharedKey>,
+    mut pinger: Option<Pinger>,
+    timer: Option<Arc<dyn Timer>>,
+    agreed: oneshot::Sender<Result<Agreement, Incompatibility>>,
+    peer_hello: oneshot::Sender<Bytes>,
+    outbound: Arc<Scheduler>,
+    mut channels: Channels<Bytes>,
+    stream_error: Arc<Latch<SerdeReadError<Arc<E>>>>,
+) where
+    T: TryStream<Ok = Bytes, Error = SerdeReadError<Arc<E>>> + Unpin,
+{
+    let mut sink_closed = sink_closed.fuse();
+    let mut agreed = Some(agreed);
+    let mut peer_hello = Some(peer_hello);
+    let max_frame_size = handshake.max_frame_size();
+    let max_wire_frame_size = max_wire_frame_size(max_frame_size, key.is_some());
+    let mut agreement: Option<Agreement> = None;
+    let mut opener: Option<Opener> = None;
+    let mut outbound = Some(outbound);
+    let mut expiry = timer.map(Expiry::new);
+    let mut pending = VecDeque::new();
+    let mut waiting = vec![];
+    let mut shutting_down = false;
+    let mut detached = false;
+
+    loop {
+        let event = {
+            let mut frame = incoming.next();
+            let mut command = commands.next();
+            // Left terminated while not pinging, so that it doesn't keep the
+            // loop from completing.
+            let mut tick = match &mut pinger {
+                Some(pinger) if pinger.is_running() => {
+                    poll_fn(move |cx| pinger.poll_tick(cx)).fuse()
+                }
+                _ => Fuse::terminated(),
+            };
+            let mut written = match &outbound {
+                Some(outbound) => poll_fn(move |cx| outbound.poll_written(cx)).fuse(),
+                None => Fuse::terminated(),
+            };
+            let mut expired = match (&mut expiry, channels.deadline()) {
+                (Some(expiry), Some(deadline)) => {
+                    poll_fn(move |cx| expiry.poll_deadline(deadline, cx)).fuse()
+                }
+                _ => Fuse::terminated(),
+            };
+
+            select! {
+                frame = frame => Event::Frame(frame),
+                command = command => Event::Command(command),
+                tick = tick => Event::Tick(tick),
+                handles = written => Event::Written(handles),
+                _ = expired => Event::Expiry,
+                _ = sink_closed => Event::SinkClosed,
+                complete => Event::Complete,
+            }
+        };
+
+        match event {
+            Event::Frame(Some(Ok(data))) => {
+                // The opener is only set up once the handshake was agreed upon,
+                // as every frame following it is encrypted.
+                let data = match &mut opener {
+                    Some(_) if data.len() > max_wire_frame_size => {
+                        Err(SerdeReadError::Oversized(data.len()))
+                    }
+                    Some(opener) => opener.open(&data).ok_or(SerdeReadError::Authentication),
+                    None => Ok(data),
+                };
+
+                // Checksums are verified before the handle is decoded, so that a
+                // corrupted handle can't route the frame to the wrong context.
+                let data = match (agreement, data) {
+                    (Some(agreement), Ok(data)) if agreement.checksums => checksum::verify(data)
+                        .map_err(|data| SerdeReadError::Corrupt {
+                            handle: agreement.header.decode(&data).map(|(handle, _)| handle),
+                        }),
+                    (_, data) => data,
+                };
+
+                let result = match (agreement.map(|agreement| agreement.header), data) {
+                    (_, Err(e)) => Err(e),
+                    (Some(_), Ok(data)) if data.len() > max_frame_size => {
+                        Err(SerdeReadError::Oversized(data.len()))
+                    }
+                    (Some(header), Ok(data)) => match header.decode(&data) {
+                        Some((CONTROL, offset)) => match from_slice(&data[offset..]) {
+                            Ok(Control::Close(id)) => {
+                                channels.close_remote(ContextHandle(id));
+                                Ok(())
+                            }
+                            Ok(Control::Credit(id, frames)) => {
+                                channels.grant(ContextHandle(id), frames);
+                                Ok(())
+                            }
+                            Ok(Control::Goodbye) => {
+                                incoming.stop();
+                                channels.close_all();
+                                Ok(())
+                            }
+                            Ok(Control::Ping(sequence)) => {
+                                if !shutting_down {
+                                    pending.push_back(Control::Pong(sequence));
+                                }
+                                Ok(())
+                            }
+                            Ok(Control::Pong(sequence)) => {
+                                if let Some(pinger) = &mut pinger {
+                                    pinger.pong(sequence);
+                                }
+                                Ok(())
+                            }
+                            Err(e) => Err(SerdeReadError::Serde {
+                                handle: CONTROL,
+                                length: data.len() - offset,
+                                source: Arc::new(e),
+                            }),
+                        },
+                        Some((handle, offset)) => {
+                            match channels.reassemble(handle, data.slice(offset..)) {
+                                Ok(Some(item)) => channels.send(handle, item).await,
+                                Ok(None) => Ok(()),
+                                Err(e) => Err(e),
+                            }
+                        }
+                        None => Err(SerdeReadError::Insufficient),
+                    },
+                    (None, Ok(data)) => {
+                        let outcome =
+                            Handshake::decode(&data).and_then(|peer| handshake.agree(&peer));
+
+                        if let Ok(terms) = &outcome {
+                            agreement = Some(*terms);
+                            if let Some(pinger) = &mut pinger {
+                                pinger.start();
+                            }
+                            opener = key.as_ref().zip(terms.nonce_prefix).map(|(key, prefix)| {
+                                Opener::new(key, prefix, transcript(&data, &handshake.encode()))
+                            });
+                            if let Some(peer_hello) = peer_hello.take() {
+                                let _ = peer_hello.send(data.clone());
+                            }
+                        }
+
+                        let result = outcome
+                            .as_ref()
+                            .map(|_| ())
+                            .map_err(|e| SerdeReadError::Incompatible(e.clone()));
+
+                        if let Some(agreed) = agreed.take() {
+                            let _ = agreed.send(outcome);
+                        }
+
+                        result
+                    }
+                };
+
+                if let Err(e) = result {
+                    stream_error.set(e);
+                    incoming.stop();
+                    channels.close_all();
+                }
+            }
+            Event::Frame(Some(Err(e))) => {
+                stream_error.set(e);
+                channels.close_all();
+            }
+            Event::Frame(None) => channels.close_all(),
+            Event::Command(Some(Command::Open(handle, channel, context))) => {
+                channels.open(handle, channel, context).await;
+            }
+            Event::Command(Some(Command::Close(handle))) => {
+                channels.close_local(handle);
+                if !shutting_down {
+                    pending.push_back(Control::Close(handle.0));
+                }
+            }
+            Event::Command(Some(Command::Consumed(handle))) => {
+                if let Some(frames) = channels.consume(handle) {
+                    if !shutting_down {
+                        pending.push_back(Control::Credit(handle.0, frames));
+                    }
+                }
+            }
+            Event::Command(Some(Command::Shutdown(done))) => {
+                waiting.push(done);
+                if !shutting_down {
+                    shutting_down = true;
+                    pending.push_back(Control::Goodbye);
+                    incoming.stop();
+                    channels.close_all();
+                }
+                // The goodbye can't be written once the sink has closed, so
+                // there is nothing left to wait for.
+                if sink_closed.is_terminated() {
+                    break;
+                }
+            }
+            Event::Command(None) => detached = true,
+            Event::Tick(Tick::Ping(sequence)) => pending.push_back(Control::Ping(sequence)),
+            Event::Tick(Tick::Expired) => {
+                stream_error.set(SerdeReadError::Timeout);
+                incoming.stop();
+                channels.close_all();
+            }
+            Event::Written(handles) => {
+                for handle in handles {
+                    channels.sent_close(handle);
+                }
+            }
+            Event::Expiry => channels.expire(),
+            Event::SinkClosed => {
+                channels.release_writers();
+                if shutting_down {
+                    break;
+                }
+            }
+            Event::Complete => break,
+        }
+
+        for (handle, frames) in channels.take_refunds() {
+            if !shutting_down {
+                pending.push_back(Control::Credit(handle.0, frames));
+            }
+        }
+
+        // Should the incoming stream end before the peer's handshake arrived,
+        // the handshake fails rather than waiting for it forever. A closed
+        // sink alone doesn't fail it, as the error that closed it usually
+        // ends the stream too, and is reported from there.
+        if agreement.is_none() && incoming.is_terminated() {
+            agreed.take();
+        }
+
+        // Pings can no longer be answered once either direction has ended.
+        if incoming.is_terminated() || sink_closed.is_terminated() {
+            if let Some(pinger) = &mut pinger {
+                pinger.stop();
+            }
+        }
+
+        // Control frames can't be encoded before a header has been agreed upon,
+        // in which case the peer isn't expecting any anyway.
+        if let (Some(outbound), Some(agreement)) = (&outbound, agreement) {
+            flush(outbound, &mut pending, &mut channels, agreement);
+        }
+
+        if shutting_down || detached {
+            if let Some(outbound) = outbound.take() {
+                outbound.close();
+            }
+        }
+    }
+
+    if let Some(outbound) = outbound {
+        outbound.close();
+    }
+
+    for done in waiting {
+        let _ = done.send(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn channels() -> (Channels<Bytes>, Arc<AtomicU64>, Arc<Handles>) {
+        let discarded = Arc::new(AtomicU64::new(0));
+        let handles = Arc::new(Handles::new(1));
+        let channels = Channels::new(BufferLimits::default(), discarded.clone(), handles.clone());
+
+        (channels, discarded, handles)
+    }
+
+    fn send(channels: &mut Channels<Bytes>, handle: ContextHandle) {
+        block_on(channels.send::<()>(handle, Bytes::from_static(b"item"))).unwrap();
+    }
+
+    #[test]
+    fn contexts_are_evicted_once_closed_by_both_ends() {
+        let (mut channels, discarded, handles) = channels();
+        let handle = ContextHandle(2);
+
+        handles.claim(handle).unwrap();
+        channels.close_local(handle);
+        channels.queued_close(handle);
+        assert!(channels.slots.contains_key(&handle));
+
+        // Frames the peer wrote before learning of the close are dropped.
+        send(&mut channels, handle);
+        assert_eq!(discarded.load(Ordering::Relaxed), 1);
+
+        channels.close_remote(handle);
+        assert!(channels.slots.is_empty());
+        assert_eq!(handles.claim(handle), Ok(()));
+    }
+
+    #[test]
+    fn local_handles_are_released_once_their_close_was_written() {
+        let (mut channels, _, handles) = channels();
+        let handle = handles.allocate(u64::MAX).unwrap();
+
+        channels.close_remote(handle);
+        channels.close_local(handle);
+        channels.queued_close(handle);
+        assert_ne!(handles.allocate(u64::MAX), Ok(handle));
+
+        channels.sent_close(handle);
+        assert_eq!(handles.allocate(u64::MAX), Ok(handle));
+    }
+}
@@ -0,0 +1,361 @@
+use super::{fragment::Fragments, ContextHandle};
+use futures::Stream;
+use std::{
+    collections::{HashMap, VecDeque},
+    mem::take,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+/// Frames queued by a single context.
+struct Queue {
+    items: VecDeque<Fragments>,
+    /// Number of frames left to write from `items`.
+    frames: usize,
+    /// Number of frames written from this queue per round.
+    weight: u32,
+    /// Number of frames written from this queue in the current round.
+    sent: u32,
+    /// Whether the context has been closed, so the queue can be dropped once
+    /// it is empty.
+    closing: bool,
+    wakers: Vec<Waker>,
+}
+
+impl Queue {
+    fn new() -> Self {
+        Queue {
+            items: VecDeque::new(),
+            frames: 0,
+            weight: 1,
+            sent: 0,
+            closing: false,
+            wakers: vec![],
+        }
+    }
+
+    fn wake(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+
+    fn register(&mut self, cx: &mut Context) {
+        if !self.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            self.wakers.push(cx.waker().clone());
+        }
+    }
+}
+
+struct State {
+    queues: HashMap<ContextHandle, Queue>,
+    /// Handles of the queues with frames to write, in the order they are
+    /// served.
+    ready: VecDeque<ContextHandle>,
+    /// Control frames that aren't tied to the frames of any context, written
+    /// ahead of everything else.
+    control: VecDeque<Vec<u8>>,
+    /// Written once every queue has drained, after which the sink is closed.
+    last: Option<Vec<u8>>,
+    capacity: usize,
+    closed: bool,
+    /// Whether queued frames were dropped as the sink failed or closed.
+    aborted: bool,
+    waker: Option<Waker>,
+    /// Handles of the contexts whose close has been handed to the sink, yet
+    /// to be taken by the router.
+    written: Vec<ContextHandle>,
+    router: Option<Waker>,
+}
+
+/// Interleaves the frames written by the contexts of a connection.
+///
+/// Each context queues its frames separately, and the queues with frames
+/// waiting are served round-robin, so a context streaming a large volume of
+/// data can't hold up the others. A queue may write as many frames per round
+/// as its weight.
+pub(super) struct Scheduler {
+    state: Mutex<State>,
+}
+
+impl Scheduler {
+    /// Creates a scheduler letting each context queue up to `capacity`
+    /// frames before its writers have to wait.
+    pub(super) fn new(capacity: usize) -> Self {
+        Scheduler {
+            state: Mutex::new(State {
+                queues: HashMap::new(),
+                ready: VecDeque::new(),
+                control: VecDeque::new(),
+                last: None,
+                capacity: capacity.max(1),
+                closed: false,
+                aborted: false,
+                waker: None,
+                written: vec![],
+                router: None,
+            }),
+        }
+    }
+
+    fn wake(state: &mut State) {
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn enqueue(state: &mut State, handle: ContextHandle, item: Fragments) {
+        let queue = state.queues.entry(handle).or_insert_with(Queue::new);
+
+        if queue.items.is_empty() {
+            state.ready.push_back(handle);
+        }
+
+        queue.frames += item.len();
+        queue.items.push_back(item);
+        Self::wake(state);
+    }
+
+    /// Sets the number of frames written from the queue of `handle` per
+    /// round.
+    pub(super) fn set_weight(&self, handle: ContextHandle, weight: u32) {
+        let mut state = self.state.lock().unwrap();
+        let queue = state.queues.entry(handle).or_insert_with(Queue::new);
+
+        queue.weight = weight.max(1);
+    }
+
+    /// Resolves once the queue of `handle` has room for another item, or
+    /// fails if the connection has closed. Each fragment of an item counts
+    /// towards the capacity of the queue.
+    pub(super) fn poll_ready(
+        &self,
+        handle: ContextHandle,
+        cx: &mut Context,
+    ) -> Poll<Result<(), ()>> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.closed {
+            return Poll::Ready(Err(()));
+        }
+
+        let capacity = state.capacity;
+
+        match state.queues.get_mut(&handle) {
+            Some(queue) if queue.frames >= capacity => {
+                queue.register(cx);
+                Poll::Pending
+            }
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    /// Queues an item written to the context with the given handle, to be
+    /// split into its fragments as they are written.
+    pub(super) fn push(&self, handle: ContextHandle, item: Fragments) -> Result<(), ()> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.closed {
+            return Err(());
+        }
+
+        Self::enqueue(&mut state, handle, item);
+
+        Ok(())
+    }
+
+    /// Resolves once every frame queued by the context with the given handle
+    /// has been handed to the sink.
+    pub(super) fn poll_flush(
+        &self,
+        handle: ContextHandle,
+        cx: &mut Context,
+    ) -> Poll<Result<(), ()>> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.aborted {
+            return Poll::Ready(Err(()));
+        }
+
+        match state.queues.get_mut(&handle) {
+            Some(queue) if !queue.items.is_empty() => {
+                queue.register(cx);
+                Poll::Pending
+            }
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    /// Queues a control frame ahead of the frames of every context.
+    pub(super) fn push_control(&self, frame: Vec<u8>) -> Result<(), ()> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.closed {
+            return Err(());
+        }
+
+        state.control.push_back(frame);
+        Self::wake(&mut state);
+
+        Ok(())
+    }
+
+    /// Queues the frame announcing that the context with the given handle was
+    /// closed behind the frames it wrote, so the peer receives those first.
+    pub(super) fn push_close(&self, handle: ContextHandle, frame: Vec<u8>) -> Result<(), ()> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.closed {
+            return Err(());
+        }
+
+        Self::enqueue(&mut state, handle, Fragments::whole(frame));
+
+        if let Some(queue) = state.queues.get_mut(&handle) {
+            queue.closing = true;
+        }
+
+        Ok(())
+    }
+
+    /// Writes `frame` once every queued frame has been written, then closes
+    /// the sink.
+    pub(super) fn finish(&self, frame: Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+
+        if !state.closed {
+            state.last = Some(frame);
+            state.closed = true;
+            Self::wake(&mut state);
+        }
+    }
+
+    /// Closes the sink once every queued frame has been written, failing any
+    /// further writes.
+    pub(super) fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        state.closed = true;
+        Self::wake(&mut state);
+    }
+
+    /// Drops every queued frame once the sink has failed or closed, waking
+    /// writers so they observe it.
+    fn abort(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        state.closed = true;
+        state.aborted = true;
+        state.control.clear();
+        state.ready.clear();
+        state.last = None;
+
+        for (_, mut queue) in take(&mut state.queues) {
+            queue.wake();
+        }
+    }
+
+    /// Resolves with the handles of the contexts whose close has been handed
+    /// to the sink since the last call, after which no frame queued later can
+    /// reach the peer ahead of it.
+    pub(super) fn poll_written(&self, cx: &mut Context) -> Poll<Vec<ContextHandle>> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.written.is_empty() {
+            state.router = Some(cx.waker().clone());
+            Poll::Pending
+        } else {
+            Poll::Ready(take(&mut state.written))
+        }
+    }
+
+    fn poll_next(&self, cx: &mut Context) -> Poll<Option<Vec<u8>>> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        if let Some(frame) = state.control.pop_front() {
+            return Poll::Ready(Some(frame));
+        }
+
+        if let Some(&handle) = state.ready.front() {
+            let queue = state.queues.get_mut(&handle).unwrap();
+            let (frame, last) = queue.items.front_mut().unwrap().next();
+
+            if last {
+                queue.items.pop_front();
+            }
+
+            queue.frames -= 1;
+            queue.sent += 1;
+            queue.wake();
+
+            if queue.items.is_empty() {
+                queue.sent = 0;
+                state.ready.pop_front();
+
+                if queue.closing {
+                    state.queues.remove(&handle);
+                    state.written.push(handle);
+
+                    if let Some(waker) = state.router.take() {
+                        waker.wake();
+                    }
+                }
+            } else if queue.sent >= queue.weight {
+                queue.sent = 0;
+                state.ready.rotate_left(1);
+            }
+
+            return Poll::Ready(Some(frame));
+        }
+
+        if state.closed {
+            return Poll::Ready(state.last.take());
+        }
+
+        state.waker = Some(cx.waker().clone());
+
+        Poll::Pending
+    }
+}
+
+/// The frames to write to the sink, in the order chosen by a `Scheduler`.
+pub(super) struct Drain(pub(super) Arc<Scheduler>);
+
+impl Stream for Drain {
+    type Item = Vec<u8>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Vec<u8>>> {
+        self.0.poll_next(cx)
+    }
+}
+
+impl Drop for Drain {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+
+    #[test]
+    fn closes_are_reported_once_written() {
+        let scheduler = Scheduler::new(4);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let handle = ContextHandle(1);
+
+        scheduler.push(handle, Fragments::whole(vec![1])).unwrap();
+        scheduler.push_close(handle, vec![2]).unwrap();
+        scheduler.set_weight(handle, 2);
+
+        assert_eq!(scheduler.poll_next(&mut cx), Poll::Ready(Some(vec![1])));
+        assert_eq!(scheduler.poll_written(&mut cx), Poll::Pending);
+        assert_eq!(scheduler.poll_next(&mut cx), Poll::Ready(Some(vec![2])));
+        assert_eq!(scheduler.poll_written(&mut cx), Poll::Ready(vec![handle]));
+        assert_eq!(scheduler.poll_written(&mut cx), Poll::Pending);
+    }
+}
@@ -0,0 +1,114 @@
+//! Fixtures shared by the integration tests and the benchmarks.
+
+use futures::{
+    channel::oneshot,
+    executor::{block_on, ThreadPool},
+    task::SpawnExt,
+};
+use protocol_mve_transport::{
+    memory::{self, Disconnected, Faults},
+    Transport, TransportBuilder,
+};
+use std::{
+    any::Any,
+    convert::Infallible,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+pub type Connection = Transport<ThreadPool, Disconnected, Disconnected, Root>;
+
+/// Hands the root context of a connection out to the caller.
+pub struct Root(pub Box<dyn Any + Send>);
+
+pub struct Grab;
+
+impl<C: Clone + Send + 'static> protocol::Future<C> for Grab {
+    type Ok = Root;
+    type Error = Infallible;
+
+    fn poll(self: Pin<&mut Self>, _: &mut Context, ctx: &mut C) -> Poll<Result<Root, Infallible>> {
+        Poll::Ready(Ok(Root(Box::new(ctx.clone()))))
+    }
+}
+
+impl<C: Clone + Send + 'static> protocol::Coalesce<C> for Root {
+    type Future = Grab;
+
+    fn coalesce() -> Grab {
+        Grab
+    }
+}
+
+pub struct Hand(Option<Box<dyn Any + Send>>);
+
+pub struct Done;
+
+impl<C: Clone + Send + 'static> protocol::Future<C> for Hand {
+    type Ok = Done;
+    type Error = Infallible;
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        _: &mut Context,
+        ctx: &mut C,
+    ) -> Poll<Result<Done, Infallible>> {
+        if let Some(Ok(sender)) = self
+            .0
+            .take()
+            .map(|item| item.downcast::<oneshot::Sender<C>>())
+        {
+            let _ = sender.send(ctx.clone());
+        }
+        Poll::Ready(Ok(Done))
+    }
+}
+
+impl<C> protocol::Future<C> for Done {
+    type Ok = ();
+    type Error = Infallible;
+
+    fn poll(self: Pin<&mut Self>, _: &mut Context, _: &mut C) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<C: Clone + Send + 'static> protocol::Unravel<C> for Root {
+    type Target = Hand;
+    type Finalize = Done;
+
+    fn unravel(self) -> Hand {
+        Hand(Some(self.0))
+    }
+}
+
+/// Connects an `Unravel` end and a `Coalesce` end configured by the given
+/// builders over an in-memory link, returning their root contexts in that
+/// order.
+pub fn connect_ends(
+    pool: &ThreadPool,
+    unravel: TransportBuilder<ThreadPool>,
+    coalesce: TransportBuilder<ThreadPool>,
+) -> (Connection, Connection, Faults) {
+    let (sender, receiver) = oneshot::channel::<Connection>();
+
+    let (coalesce, unravel, faults) =
+        memory::connect_with(Root(Box::new(sender)), coalesce, unravel);
+    pool.spawn(async move {
+        let _ = unravel.await;
+    })
+    .unwrap();
+
+    let b = *block_on(coalesce).ok().unwrap().0.downcast().unwrap();
+
+    (block_on(receiver).unwrap(), b, faults)
+}
+
+/// Connects two ends both configured by `configure`, see `connect_ends`.
+pub fn connect_with(
+    pool: &ThreadPool,
+    configure: impl Fn(TransportBuilder<ThreadPool>) -> TransportBuilder<ThreadPool>,
+) -> (Connection, Connection, Faults) {
+    let builder = || configure(TransportBuilder::new(pool.clone()));
+    connect_ends(pool, builder(), builder())
+}
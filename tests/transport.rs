@@ -0,0 +1,2027 @@
+mod common;
+
+use common::{connect_ends, connect_with, Connection, Root};
+use futures::{
+    channel::{mpsc, oneshot},
+    executor::{block_on, ThreadPool},
+    future::{join, join_all, pending, poll_fn, ready, BoxFuture},
+    io::{AsyncRead, AsyncWrite},
+    stream::{self, BoxStream, IntoAsyncRead},
+    task::SpawnExt,
+    FutureExt, SinkExt, StreamExt, TryStreamExt,
+};
+use protocol::{
+    CloneContext, Finalize, Fork, Join, Notify, Read, ReferenceContext, ShareContext, Write,
+};
+use protocol_mve_transport::{
+    memory::{self, Disconnected, Faults},
+    Bincode, Borrowed, BufferLimits, Coalesce, Fingerprint, FramedRead, FramedWrite, FramingError,
+    HandleError, Incompatibility, Notification, SerdeReadError, SerdeWriteError, Transport,
+    TransportBuilder, Unravel, WithSpawnError,
+};
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+type Gated = Transport<ThreadPool, Disconnected, mpsc::SendError, Root>;
+
+/// A protocol type transferred as a single item written to its context.
+#[derive(Debug, PartialEq, Eq)]
+struct Value(u64);
+
+struct Put(Option<u64>);
+
+struct Flush;
+
+struct Get;
+
+impl<C: Write<u64> + Unpin> protocol::Future<C> for Put {
+    type Ok = Flush;
+    type Error = C::Error;
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        ctx: &mut C,
+    ) -> Poll<Result<Flush, C::Error>> {
+        let mut ctx = Pin::new(ctx);
+
+        if let Some(item) = self.0 {
+            futures::ready!(ctx.as_mut().poll_ready(cx))?;
+            ctx.write(item)?;
+            self.0 = None;
+        }
+
+        Poll::Ready(Ok(Flush))
+    }
+}
+
+impl<C: Write<u64> + Unpin> protocol::Future<C> for Flush {
+    type Ok = ();
+    type Error = C::Error;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context, ctx: &mut C) -> Poll<Result<(), C::Error>> {
+        Pin::new(ctx).poll_flush(cx)
+    }
+}
+
+impl<C: Read<u64> + Unpin> protocol::Future<C> for Get {
+    type Ok = Value;
+    type Error = C::Error;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context, ctx: &mut C) -> Poll<Result<Value, C::Error>> {
+        Pin::new(ctx).read(cx).map_ok(Value)
+    }
+}
+
+impl<C: Write<u64> + Unpin> protocol::Unravel<C> for Value {
+    type Target = Put;
+    type Finalize = Flush;
+
+    fn unravel(self) -> Put {
+        Put(Some(self.0))
+    }
+}
+
+impl<C: Read<u64> + Unpin> protocol::Coalesce<C> for Value {
+    type Future = Get;
+
+    fn coalesce() -> Get {
+        Get
+    }
+}
+
+/// Unravels a protocol type carried over a context of its own, the way
+/// `protocol` transfers futures, streams and closures: forks the context and
+/// writes its handle to the parent, then finalizes by driving the type over
+/// the forked context.
+struct Transfer {
+    forked: Option<(Connection, Option<u64>)>,
+    drive: Option<Box<dyn FnOnce(Connection) -> Drive + Send>>,
+}
+
+impl Transfer {
+    fn new<F>(drive: impl FnOnce(Connection) -> F + Send + 'static) -> Self
+    where
+        F: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        Transfer {
+            forked: None,
+            drive: Some(Box::new(move |child| Drive(drive(child).boxed()))),
+        }
+    }
+}
+
+impl protocol::Future<Connection> for Transfer {
+    type Ok = Drive;
+    type Error = String;
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        ctx: &mut Connection,
+    ) -> Poll<Result<Drive, String>> {
+        let this = &mut *self;
+
+        if this.forked.is_none() {
+            let mut fork = ctx.fork_owned();
+            let (child, id) =
+                futures::ready!(Pin::new(&mut fork).poll(cx, ctx)).map_err(|e| e.to_string())?;
+            this.forked = Some((child, Some(id)));
+        }
+
+        let (_, id) = this.forked.as_mut().unwrap();
+
+        if let Some(item) = *id {
+            futures::ready!(Write::<u64>::poll_ready(Pin::new(&mut *ctx), cx))
+                .map_err(|e| e.to_string())?;
+            Pin::new(&mut *ctx).write(item).map_err(|e| e.to_string())?;
+            *id = None;
+        }
+
+        futures::ready!(Write::<u64>::poll_flush(Pin::new(&mut *ctx), cx))
+            .map_err(|e| e.to_string())?;
+
+        let (child, _) = this.forked.take().unwrap();
+
+        Poll::Ready(Ok((this.drive.take().unwrap())(child)))
+    }
+}
+
+/// Drives a protocol type transferred by `Transfer` over its own context.
+struct Drive(BoxFuture<'static, Result<(), String>>);
+
+impl<C> protocol::Future<C> for Drive {
+    type Ok = ();
+    type Error = String;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context, _: &mut C) -> Poll<Result<(), String>> {
+        self.0.poll_unpin(cx)
+    }
+}
+
+/// Coalesces a protocol type carried over a context of its own, joining the
+/// context whose handle is read from the parent.
+struct Accept<P>(Option<fn(Connection) -> P>);
+
+impl<P> protocol::Future<Connection> for Accept<P> {
+    type Ok = P;
+    type Error = String;
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        ctx: &mut Connection,
+    ) -> Poll<Result<P, String>> {
+        let id = futures::ready!(Read::<u64>::read(Pin::new(&mut *ctx), cx))
+            .map_err(|e| e.to_string())?;
+
+        let mut join = ctx.join_owned(id);
+        let child =
+            futures::ready!(Pin::new(&mut join).poll(cx, ctx)).map_err(|e| e.to_string())?;
+
+        Poll::Ready(Ok((self.0.take().unwrap())(child)))
+    }
+}
+
+/// A value that only becomes available later, written once it has.
+struct Deferred(BoxFuture<'static, u64>);
+
+impl protocol::Unravel<Connection> for Deferred {
+    type Target = Transfer;
+    type Finalize = Drive;
+
+    fn unravel(self) -> Transfer {
+        Transfer::new(move |mut child| async move {
+            let value = self.0.await;
+            send(&mut child, value).await.map_err(|e| e.to_string())
+        })
+    }
+}
+
+impl protocol::Coalesce<Connection> for Deferred {
+    type Future = Accept<Deferred>;
+
+    fn coalesce() -> Accept<Deferred> {
+        Accept(Some(|mut child| {
+            Deferred(async move { receive::<u64, _>(&mut child).await.unwrap() }.boxed())
+        }))
+    }
+}
+
+/// A sequence of values, each written as it is produced and followed by
+/// `None` once the sequence has ended.
+struct Items(BoxStream<'static, u64>);
+
+impl protocol::Unravel<Connection> for Items {
+    type Target = Transfer;
+    type Finalize = Drive;
+
+    fn unravel(self) -> Transfer {
+        Transfer::new(move |mut child| async move {
+            let mut items = self.0;
+
+            while let Some(item) = items.next().await {
+                send(&mut child, Some(item))
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+
+            send(&mut child, None::<u64>)
+                .await
+                .map_err(|e| e.to_string())
+        })
+    }
+}
+
+impl protocol::Coalesce<Connection> for Items {
+    type Future = Accept<Items>;
+
+    fn coalesce() -> Accept<Items> {
+        Accept(Some(|child| {
+            Items(
+                stream::unfold(child, |mut child| async move {
+                    let item = receive::<Option<u64>, _>(&mut child).await.unwrap();
+                    item.map(|item| (item, child))
+                })
+                .boxed(),
+            )
+        }))
+    }
+}
+
+/// A function called from the other end, which writes each argument and
+/// reads back the result. Calls are answered until the caller drops it.
+struct Callback(Box<dyn FnMut(u64) -> BoxFuture<'static, u64> + Send>);
+
+impl protocol::Unravel<Connection> for Callback {
+    type Target = Transfer;
+    type Finalize = Drive;
+
+    fn unravel(self) -> Transfer {
+        Transfer::new(move |mut child| async move {
+            let mut call = self.0;
+
+            while let Ok(argument) = receive::<u64, _>(&mut child).await {
+                let result = call(argument).await;
+                send(&mut child, result).await.map_err(|e| e.to_string())?;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl protocol::Coalesce<Connection> for Callback {
+    type Future = Accept<Callback>;
+
+    fn coalesce() -> Accept<Callback> {
+        Accept(Some(|child| {
+            let child = Arc::new(futures::lock::Mutex::new(child));
+
+            Callback(Box::new(move |argument| {
+                let child = child.clone();
+
+                async move {
+                    let mut child = child.lock().await;
+                    send(&mut *child, argument).await.unwrap();
+                    receive::<u64, _>(&mut *child).await.unwrap()
+                }
+                .boxed()
+            }))
+        }))
+    }
+}
+
+fn connect(pool: &ThreadPool) -> (Connection, Connection, Faults) {
+    connect_with(pool, |builder| builder)
+}
+
+async fn run<C, F: protocol::Future<C> + Unpin>(
+    mut fut: F,
+    ctx: &mut C,
+) -> Result<F::Ok, F::Error> {
+    poll_fn(|cx| Pin::new(&mut fut).poll(cx, ctx)).await
+}
+
+async fn send<T, C: Write<T> + Unpin>(ctx: &mut C, item: T) -> Result<(), C::Error> {
+    poll_fn(|cx| Pin::new(&mut *ctx).poll_ready(cx)).await?;
+    Pin::new(&mut *ctx).write(item)?;
+    poll_fn(|cx| Pin::new(&mut *ctx).poll_flush(cx)).await
+}
+
+async fn receive<T, C: Read<T> + Unpin>(ctx: &mut C) -> Result<T, C::Error> {
+    poll_fn(|cx| Pin::new(&mut *ctx).read(cx)).await
+}
+
+/// Forks a context from `a` and joins it from `b`, exchanging its handle over
+/// the given parent contexts.
+async fn open(a: &mut Connection, b: &mut Connection) -> (Connection, Connection) {
+    let (child, id) = run(a.fork_owned(), a).await.ok().unwrap();
+    send(a, id).await.unwrap();
+
+    let id = receive::<u64, _>(b).await.unwrap();
+    let peer = run(b.join_owned(id), b).await.ok().unwrap();
+
+    (child, peer)
+}
+
+/// Keepalive delays along with their durations, each elapsing once fired.
+type Delays = mpsc::UnboundedReceiver<(Duration, oneshot::Sender<()>)>;
+
+/// A keepalive timer whose delays only elapse once the test fires them
+/// through the returned receiver, rather than on the wall clock. Delays that
+/// are dropped without being fired never elapse.
+fn clock() -> (
+    impl Fn(Duration) -> BoxFuture<'static, ()> + Clone + Send + Sync + 'static,
+    Delays,
+) {
+    let (sender, receiver) = mpsc::unbounded();
+
+    let timer = move |duration| {
+        let (fire, fired) = oneshot::channel();
+        let _ = sender.unbounded_send((duration, fire));
+
+        async move {
+            if fired.await.is_err() {
+                pending::<()>().await;
+            }
+        }
+        .boxed()
+    };
+
+    (timer, receiver)
+}
+
+/// Sits between the sink of an `Unravel` end and its link, passing frames on
+/// only while open and logging each. The first byte of a frame is the handle
+/// of its context plus one for handles below 127.
+struct Gate {
+    toggle: mpsc::UnboundedSender<bool>,
+    log: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl Gate {
+    fn open(&self) {
+        self.toggle.unbounded_send(true).unwrap();
+    }
+
+    /// Stops passing frames on. A few frames already taken from the
+    /// transport may still get through.
+    fn close(&self) {
+        self.toggle.unbounded_send(false).unwrap();
+    }
+
+    /// Stops passing frames on for good, failing the sink of the transport
+    /// once it writes again.
+    fn cut(self) {
+        self.close();
+    }
+
+    /// Returns the handles of the frames passed on so far that were written
+    /// to one of `handles`, in order.
+    fn written(&self, handles: &[u64]) -> Vec<u64> {
+        self.log
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|frame| u64::from(frame[0]).wrapping_sub(1))
+            .filter(|handle| handles.contains(handle))
+            .collect()
+    }
+
+    /// Returns the frames passed on so far that were written to `handle`.
+    fn frames(&self, handle: u64) -> Vec<Vec<u8>> {
+        self.log
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|frame| u64::from(frame[0]).wrapping_sub(1) == handle)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Like `connect_with`, but with the frames written by the `Unravel` end
+/// passing through a `Gate`.
+fn connect_gated(
+    pool: &ThreadPool,
+    configure: impl Fn(TransportBuilder<ThreadPool>) -> TransportBuilder<ThreadPool>,
+) -> (Gated, Connection, Gate) {
+    let ((a_stream, mut a_sink), (b_stream, b_sink)) = memory::pair();
+    let (sink, mut frames) = mpsc::channel::<Vec<u8>>(0);
+    let (toggle, mut toggles) = mpsc::unbounded();
+    let log = Arc::new(Mutex::new(vec![]));
+    let gate = Gate {
+        toggle,
+        log: log.clone(),
+    };
+
+    pool.spawn(async move {
+        let mut open = true;
+
+        loop {
+            while let Ok(state) = toggles.try_recv() {
+                open = state;
+            }
+
+            if !open {
+                match toggles.next().await {
+                    Some(state) => open = state,
+                    None => return,
+                }
+                continue;
+            }
+
+            match frames.next().await {
+                Some(frame) => {
+                    log.lock().unwrap().push(frame.clone());
+                    if a_sink.send(frame).await.is_err() {
+                        return;
+                    }
+                }
+                None => return,
+            }
+        }
+    })
+    .unwrap();
+
+    let (sender, receiver) = oneshot::channel::<Gated>();
+
+    let unravel = configure(TransportBuilder::new(pool.clone())).unravel(
+        a_stream,
+        sink,
+        Root(Box::new(sender)),
+    );
+    pool.spawn(async move {
+        let _ = unravel.await;
+    })
+    .unwrap();
+
+    let coalesce: Coalesce<_, _, _, Root> =
+        configure(TransportBuilder::new(pool.clone())).coalesce(b_stream, b_sink);
+    let b = *block_on(coalesce).ok().unwrap().0.downcast().unwrap();
+
+    (block_on(receiver).unwrap(), b, gate)
+}
+
+/// Like `open`, but from the `Unravel` end of a gated connection, also
+/// returning the handle of the context.
+async fn open_gated(a: &mut Gated, b: &mut Connection) -> (Gated, Connection, u64) {
+    let (child, id) = run(a.fork_owned(), a).await.ok().unwrap();
+    send(a, id).await.unwrap();
+
+    let id = receive::<u64, _>(b).await.unwrap();
+    let peer = run(b.join_owned(id), b).await.ok().unwrap();
+
+    (child, peer, id)
+}
+
+/// Writes `item` without waiting for it to reach the sink.
+async fn queue<T, C: Write<T> + Unpin>(ctx: &mut C, item: T) -> Result<(), C::Error> {
+    poll_fn(|cx| Pin::new(&mut *ctx).poll_ready(cx)).await?;
+    Pin::new(&mut *ctx).write(item)
+}
+
+#[test]
+fn handles_are_allocated_by_parity() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect(&pool);
+
+    let mut unravelled = vec![];
+    let mut coalesced = vec![];
+
+    for _ in 0..3 {
+        unravelled.push(block_on(run(a.fork_owned(), &mut a)).ok().unwrap().1);
+        coalesced.push(block_on(run(b.fork_owned(), &mut b)).ok().unwrap().1);
+    }
+
+    assert_eq!(unravelled, [1, 3, 5]);
+    assert_eq!(coalesced, [2, 4, 6]);
+}
+
+#[test]
+fn owned_shared_and_referenced_contexts_round_trip() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect(&pool);
+
+    block_on(async {
+        let (owned, id) = run(a.fork_owned(), &mut a).await.ok().unwrap();
+        send(&mut a, id).await.unwrap();
+        let id = receive::<u64, _>(&mut b).await.unwrap();
+        let joined = run(b.join_owned(id), &mut b).await.ok().unwrap();
+
+        let (shared, id) = run(a.fork_shared(), &mut a).await.ok().unwrap();
+        send(&mut a, id).await.unwrap();
+        let id = receive::<u64, _>(&mut b).await.unwrap();
+        let joined_shared = run(b.join_shared(id), &mut b).await.ok().unwrap();
+
+        let (referenced, id) = run(a.fork_ref(), &mut a).await.ok().unwrap();
+        send(&mut a, id).await.unwrap();
+        let id = receive::<u64, _>(&mut b).await.unwrap();
+        let joined_referenced = run(b.join_ref(id), &mut b).await.ok().unwrap();
+
+        let pairs = vec![
+            (owned, joined),
+            (shared, joined_shared),
+            (referenced, joined_referenced),
+        ];
+
+        for (index, (mut ours, mut theirs)) in pairs.into_iter().enumerate() {
+            let index = index as u64;
+
+            send(&mut ours, index).await.unwrap();
+            assert_eq!(receive::<u64, _>(&mut theirs).await.unwrap(), index);
+
+            send(&mut theirs, format!("reply {}", index)).await.unwrap();
+            assert_eq!(
+                receive::<String, _>(&mut ours).await.unwrap(),
+                format!("reply {}", index)
+            );
+        }
+    });
+}
+
+#[test]
+fn minimal_capacities_deliver_every_frame() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect_with(&pool, |builder| builder.outbound_capacity(0));
+
+    block_on(async {
+        let mut ours = vec![];
+        let mut theirs = vec![];
+
+        for _ in 0..4 {
+            let (child, peer) = open(&mut a, &mut b).await;
+            ours.push(child);
+            theirs.push(peer);
+        }
+
+        let writers = ours.iter_mut().map(|ctx| async move {
+            for item in 0..64u64 {
+                send(ctx, item).await.unwrap();
+            }
+        });
+        let readers = theirs.iter_mut().map(|ctx| async move {
+            for item in 0..64u64 {
+                assert_eq!(receive::<u64, _>(ctx).await.unwrap(), item);
+            }
+        });
+
+        join(join_all(writers), join_all(readers)).await;
+    });
+}
+
+#[test]
+fn frames_written_before_a_join_are_buffered_in_order() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect(&pool);
+
+    block_on(async {
+        let (mut child, id) = run(a.fork_owned(), &mut a).await.ok().unwrap();
+
+        for item in 0..16u64 {
+            send(&mut child, item).await.unwrap();
+        }
+        send(&mut a, id).await.unwrap();
+
+        let id = receive::<u64, _>(&mut b).await.unwrap();
+        let mut peer = run(b.join_owned(id), &mut b).await.ok().unwrap();
+
+        for item in 0..16u64 {
+            assert_eq!(receive::<u64, _>(&mut peer).await.unwrap(), item);
+        }
+    });
+}
+
+#[test]
+fn contexts_are_read_independently() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect(&pool);
+
+    block_on(async {
+        let mut contexts = vec![];
+
+        for _ in 0..8 {
+            contexts.push(open(&mut a, &mut b).await);
+        }
+
+        for (index, (ours, _)) in contexts.iter_mut().enumerate() {
+            for item in 0..4u64 {
+                send(ours, index as u64 * 100 + item).await.unwrap();
+            }
+        }
+
+        for (index, (_, theirs)) in contexts.iter_mut().enumerate().rev() {
+            for item in 0..4u64 {
+                assert_eq!(
+                    receive::<u64, _>(theirs).await.unwrap(),
+                    index as u64 * 100 + item
+                );
+            }
+        }
+    });
+}
+
+#[test]
+fn reordering_across_contexts_preserves_order_within_each() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, faults) = connect(&pool);
+    // Items each context may write before it has to wait for the peer.
+    let window = u64::from(BufferLimits::default().window);
+
+    faults.reorder(0x5eed);
+    faults.hold();
+
+    block_on(async {
+        let writer = async {
+            let mut children = vec![];
+
+            // Fill the window of every context while frames are held back, so
+            // that they are all reordered at once.
+            for _ in 0..16 {
+                let (mut child, id) = run(a.fork_owned(), &mut a).await.ok().unwrap();
+                send(&mut a, id).await.unwrap();
+
+                for item in 0..window {
+                    send(&mut child, item).await.unwrap();
+                }
+
+                children.push(child);
+            }
+
+            faults.release();
+
+            let tasks = children.into_iter().map(|mut child| {
+                pool.spawn_with_handle(async move {
+                    for item in window..64 {
+                        send(&mut child, item).await.unwrap();
+                    }
+                })
+                .unwrap()
+            });
+
+            join_all(tasks).await;
+        };
+
+        let reader = async {
+            let mut tasks = vec![];
+
+            for _ in 0..16 {
+                let id = receive::<u64, _>(&mut b).await.unwrap();
+                let mut peer = run(b.join_owned(id), &mut b).await.ok().unwrap();
+
+                tasks.push(
+                    pool.spawn_with_handle(async move {
+                        for item in 0..64u64 {
+                            assert_eq!(receive::<u64, _>(&mut peer).await.unwrap(), item);
+                        }
+                    })
+                    .unwrap(),
+                );
+            }
+
+            join_all(tasks).await;
+        };
+
+        join(writer, reader).await;
+    });
+}
+
+#[test]
+fn nested_contexts_are_joined_through_their_parent() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect(&pool);
+
+    block_on(async {
+        let (mut child, mut peer) = open(&mut a, &mut b).await;
+        let (mut grandchild, mut grandpeer) = open(&mut peer, &mut child).await;
+
+        send(&mut grandchild, 7u64).await.unwrap();
+        assert_eq!(receive::<u64, _>(&mut grandpeer).await.unwrap(), 7);
+
+        send(&mut grandpeer, 8u64).await.unwrap();
+        assert_eq!(receive::<u64, _>(&mut grandchild).await.unwrap(), 8);
+
+        send(&mut child, 9u64).await.unwrap();
+        assert_eq!(receive::<u64, _>(&mut peer).await.unwrap(), 9);
+    });
+}
+
+#[test]
+fn protocol_types_fork_and_join() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect(&pool);
+
+    block_on(async {
+        let (target, ()) = run(Fork::<Value>::fork(&mut a, Value(3)), &mut a)
+            .await
+            .ok()
+            .unwrap();
+        let finalize = run(target, &mut a).await.unwrap();
+        run(finalize, &mut a).await.unwrap();
+
+        let value = run(Join::<Value>::join(&mut b, ()), &mut b).await.unwrap();
+        assert_eq!(value, Value(3));
+    });
+}
+
+/// Forks `item` from `a`, spawning the future that finalizes it.
+async fn transfer<P>(a: &mut Connection, item: P)
+where
+    P: protocol::Unravel<Connection, Target = Transfer, Finalize = Drive>
+        + protocol::Coalesce<Connection>,
+{
+    let (target, ()) = run(Fork::<P>::fork(a, item), a).await.ok().unwrap();
+    let finalize = run(target, a).await.unwrap();
+    run(a.finalize(finalize), a).await.unwrap();
+}
+
+#[test]
+fn futures_round_trip() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect(&pool);
+    let (resolve, resolved) = oneshot::channel();
+
+    block_on(async {
+        transfer(&mut a, Deferred(resolved.map(Result::unwrap).boxed())).await;
+        let deferred = run(Join::<Deferred>::join(&mut b, ()), &mut b)
+            .await
+            .unwrap();
+
+        // The value is written once it becomes available, after the join.
+        resolve.send(7).unwrap();
+        assert_eq!(deferred.0.await, 7);
+    });
+}
+
+#[test]
+fn streams_round_trip() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect(&pool);
+
+    block_on(async {
+        // More items than fit the window, so the stream waits for credit.
+        transfer(&mut a, Items(stream::iter(0..256u64).boxed())).await;
+        let items = run(Join::<Items>::join(&mut b, ()), &mut b).await.unwrap();
+
+        assert_eq!(
+            items.0.collect::<Vec<_>>().await,
+            (0..256).collect::<Vec<_>>()
+        );
+    });
+}
+
+#[test]
+fn closures_round_trip() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect(&pool);
+    let (alive, dropped) = oneshot::channel::<()>();
+
+    block_on(async {
+        let double = move |argument| {
+            let _alive = &alive;
+            async move { argument * 2 }.boxed()
+        };
+        transfer(&mut a, Callback(Box::new(double))).await;
+        let mut callback = run(Join::<Callback>::join(&mut b, ()), &mut b)
+            .await
+            .unwrap();
+
+        for argument in 0..4 {
+            assert_eq!((callback.0)(argument).await, argument * 2);
+        }
+
+        // Dropping the callback closes its context, after which the other
+        // end stops answering calls and drops the closure.
+        drop(callback);
+        assert!(dropped.await.is_err());
+    });
+}
+
+#[test]
+fn notifications_round_trip() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect(&pool);
+
+    block_on(async {
+        for item in 0..4 {
+            let notification = run(Notify::<Value>::wrap(&mut a, Value(item)), &mut a)
+                .await
+                .ok()
+                .unwrap();
+            let (target, ()) = run(
+                Fork::<Notification<Value>>::fork(&mut a, notification),
+                &mut a,
+            )
+            .await
+            .ok()
+            .unwrap();
+            let finalize = run(target, &mut a).await.unwrap();
+            run(finalize, &mut a).await.unwrap();
+        }
+
+        for item in 0..4 {
+            let notification = run(Join::<Notification<Value>>::join(&mut b, ()), &mut b)
+                .await
+                .unwrap();
+            let value = run(Notify::<Value>::unwrap(&mut b, notification), &mut b)
+                .await
+                .ok()
+                .unwrap();
+            assert_eq!(value, Value(item));
+        }
+    });
+}
+
+#[test]
+fn finalized_futures_run_on_a_clone_of_the_context() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect(&pool);
+
+    block_on(async {
+        run(a.finalize(Put(Some(42))), &mut a).await.unwrap();
+
+        assert_eq!(receive::<u64, _>(&mut b).await.unwrap(), 42);
+    });
+}
+
+#[test]
+fn joining_a_handle_twice_fails() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect(&pool);
+
+    block_on(async {
+        let (_child, id) = run(a.fork_owned(), &mut a).await.unwrap();
+        let _peer = run(b.join_owned(id), &mut b).await.unwrap();
+
+        assert!(matches!(
+            run(b.join_shared(id), &mut b).await,
+            Err(HandleError::Duplicate(_))
+        ));
+        assert!(matches!(
+            run(a.join_owned(id), &mut a).await,
+            Err(HandleError::Duplicate(_))
+        ));
+        assert!(matches!(
+            run(a.join_owned(0), &mut a).await,
+            Err(HandleError::Duplicate(_))
+        ));
+    });
+}
+
+#[test]
+fn handles_are_reused_once_closed_by_both_ends() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect(&pool);
+
+    block_on(async {
+        let (child, peer) = open(&mut a, &mut b).await;
+        drop(child);
+        drop(peer);
+
+        // The handle is released once the close of either end has reached
+        // the other, which happens in the background, so pass items back and
+        // forth on the root context until it has.
+        for item in 0..100u64 {
+            let (_, id) = run(a.fork_owned(), &mut a).await.unwrap();
+            if id == 1 {
+                return;
+            }
+
+            send(&mut a, item).await.unwrap();
+            let item = receive::<u64, _>(&mut b).await.unwrap();
+            send(&mut b, item).await.unwrap();
+            assert_eq!(receive::<u64, _>(&mut a).await.unwrap(), item);
+        }
+
+        panic!("handle 1 was never reused");
+    });
+}
+
+#[test]
+fn busy_contexts_do_not_hold_up_others() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, gate) = connect_gated(&pool, |builder| builder.outbound_capacity(16));
+
+    let (busy, idle) = block_on(async {
+        let (mut busy, mut busy_peer, busy_id) = open_gated(&mut a, &mut b).await;
+        let (mut idle, mut idle_peer, idle_id) = open_gated(&mut a, &mut b).await;
+
+        gate.close();
+        for index in 0..16u64 {
+            queue(&mut busy, index).await.unwrap();
+        }
+        queue(&mut idle, 16u64).await.unwrap();
+        gate.open();
+
+        assert_eq!(receive::<u64, _>(&mut idle_peer).await.unwrap(), 16);
+        for index in 0..16 {
+            assert_eq!(receive::<u64, _>(&mut busy_peer).await.unwrap(), index);
+        }
+
+        (busy_id, idle_id)
+    });
+
+    let written = gate.written(&[busy, idle]);
+    let position = written.iter().position(|&handle| handle == idle).unwrap();
+
+    assert_eq!(written.len(), 17);
+    assert!(position < 8, "idle context written at {}", position);
+}
+
+#[test]
+fn unread_contexts_do_not_hold_up_others() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect(&pool);
+    let window = BufferLimits::default().window;
+
+    block_on(async {
+        let (mut unread, _unread_peer) = open(&mut a, &mut b).await;
+        let (mut child, mut peer) = open(&mut a, &mut b).await;
+
+        // Fill the window of a context that is never read, which the router
+        // has to hand over without waiting for it to be read.
+        for item in 0..u64::from(window) {
+            send(&mut unread, item).await.unwrap();
+        }
+
+        for item in 0..64u64 {
+            send(&mut child, item).await.unwrap();
+            assert_eq!(receive::<u64, _>(&mut peer).await.unwrap(), item);
+        }
+    });
+}
+
+#[test]
+fn priorities_weight_the_share_of_each_context() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, gate) = connect_gated(&pool, |builder| builder.outbound_capacity(16));
+
+    let (heavy, light) = block_on(async {
+        let (mut heavy, mut heavy_peer, heavy_id) = open_gated(&mut a, &mut b).await;
+        let (mut light, mut light_peer, light_id) = open_gated(&mut a, &mut b).await;
+
+        heavy.set_priority(3);
+
+        gate.close();
+        for index in 0..16u64 {
+            queue(&mut heavy, index).await.unwrap();
+        }
+        for index in 0..16u64 {
+            queue(&mut light, index).await.unwrap();
+        }
+        gate.open();
+
+        for index in 0..16 {
+            assert_eq!(receive::<u64, _>(&mut heavy_peer).await.unwrap(), index);
+            assert_eq!(receive::<u64, _>(&mut light_peer).await.unwrap(), index);
+        }
+
+        (heavy_id, light_id)
+    });
+
+    // Skip the frames that got past the gate before it closed, all of which
+    // belong to the heavy context.
+    let written = gate.written(&[heavy, light]);
+    let first = written.iter().position(|&handle| handle == light).unwrap();
+    let window = &written[first..first + 12];
+    let count = |handle| window.iter().filter(|&&other| other == handle).count();
+
+    assert_eq!((count(heavy), count(light)), (9, 3), "{:?}", written);
+}
+
+#[test]
+fn weighted_parents_announce_reused_handles_after_their_close() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, gate) = connect_gated(&pool, |builder| builder.outbound_capacity(16));
+
+    block_on(async {
+        let (mut parent, mut parent_peer, _) = open_gated(&mut a, &mut b).await;
+        parent.set_priority(4);
+
+        for round in 0..8u64 {
+            let (mut child, child_peer, _) = open_gated(&mut a, &mut b).await;
+
+            // Hold the close of the child behind a few of its frames, which
+            // the parent outweighs.
+            gate.close();
+            for index in 0..4u64 {
+                queue(&mut child, index).await.unwrap();
+            }
+            drop(child);
+            drop(child_peer);
+
+            // Give the close of the peer time to arrive, after which the
+            // handle would be reused if it were released too early.
+            for _ in 0..4 {
+                send(&mut b, round).await.unwrap();
+                assert_eq!(receive::<u64, _>(&mut a).await.unwrap(), round);
+            }
+
+            let (_next, id) = run(a.fork_owned(), &mut a).await.ok().unwrap();
+            queue(&mut parent, id).await.unwrap();
+            gate.open();
+
+            let id = receive::<u64, _>(&mut parent_peer).await.unwrap();
+            assert!(
+                run(b.join_owned(id), &mut b).await.is_ok(),
+                "round {}",
+                round
+            );
+        }
+    });
+}
+
+#[test]
+fn items_are_written_uncompressed_by_default() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, gate) = connect_gated(&pool, |builder| builder);
+    let text = "compressible ".repeat(100);
+
+    let handle = block_on(async {
+        let (mut child, mut peer, id) = open_gated(&mut a, &mut b).await;
+
+        send(&mut child, text.clone()).await.unwrap();
+        assert_eq!(receive::<String, _>(&mut peer).await.unwrap(), text);
+
+        id
+    });
+
+    // The handle and flags followed by the length-prefixed string.
+    assert_eq!(gate.frames(handle)[0].len(), 2 + 8 + text.len());
+}
+
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+#[test]
+fn large_items_are_compressed() {
+    use bytes::Bytes;
+    use protocol_mve_transport::{Compression, Payload};
+
+    let algorithms = [
+        #[cfg(feature = "zstd")]
+        Compression::Zstd,
+        #[cfg(feature = "lz4")]
+        Compression::Lz4,
+    ];
+
+    for &compression in &algorithms {
+        let pool = ThreadPool::new().unwrap();
+        let (mut a, mut b, gate) = connect_gated(&pool, |builder| {
+            builder.compression(compression).compression_threshold(64)
+        });
+        let text = "compressible ".repeat(100);
+
+        let handle = block_on(async {
+            let (mut child, mut peer, id) = open_gated(&mut a, &mut b).await;
+
+            send(&mut child, text.clone()).await.unwrap();
+            send(&mut child, Payload(Bytes::from(text.clone())))
+                .await
+                .unwrap();
+            send(&mut child, 7u64).await.unwrap();
+
+            assert_eq!(receive::<String, _>(&mut peer).await.unwrap(), text);
+            assert_eq!(
+                receive::<Payload, _>(&mut peer).await.unwrap().0,
+                text.as_bytes()
+            );
+            assert_eq!(receive::<u64, _>(&mut peer).await.unwrap(), 7);
+
+            id
+        });
+
+        let frames = gate.frames(handle);
+
+        assert_eq!(frames.len(), 3);
+        assert!(
+            frames[0].len() < text.len() / 4,
+            "{} was not compressed",
+            compression
+        );
+        assert!(
+            frames[1].len() < text.len() / 4,
+            "{} was not compressed",
+            compression
+        );
+        // The handle and flags followed by the item as is.
+        assert_eq!(frames[2].len(), 10);
+    }
+}
+
+#[test]
+fn large_items_are_fragmented_and_interleaved() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, gate) = connect_gated(&pool, |builder| {
+        builder.outbound_capacity(16).fragment_size(100)
+    });
+    let text = "fragmented ".repeat(100);
+
+    let (large, small) = block_on(async {
+        let (mut large, mut large_peer, large_id) = open_gated(&mut a, &mut b).await;
+        let (mut small, mut small_peer, small_id) = open_gated(&mut a, &mut b).await;
+
+        gate.close();
+        queue(&mut large, text.clone()).await.unwrap();
+        queue(&mut small, 7u64).await.unwrap();
+        gate.open();
+
+        assert_eq!(receive::<u64, _>(&mut small_peer).await.unwrap(), 7);
+        assert_eq!(receive::<String, _>(&mut large_peer).await.unwrap(), text);
+
+        (large_id, small_id)
+    });
+
+    let fragments = gate.frames(large);
+    let written = gate.written(&[large, small]);
+
+    // The length-prefixed string split into payloads of 100 bytes, each
+    // following the handle and flags.
+    assert_eq!(fragments.len(), (8 + text.len()).div_ceil(100));
+    assert!(fragments.iter().all(|fragment| fragment.len() <= 2 + 100));
+    assert_ne!(written.last(), Some(&small), "{:?}", written);
+}
+
+#[test]
+fn fragments_count_against_the_outbound_capacity() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, gate) = connect_gated(&pool, |builder| {
+        builder.outbound_capacity(4).fragment_size(100)
+    });
+    let text = "fragmented ".repeat(100);
+
+    block_on(async {
+        let (mut child, mut peer, _) = open_gated(&mut a, &mut b).await;
+
+        gate.close();
+        queue(&mut child, text.clone()).await.unwrap();
+
+        // Far more fragments are queued than the capacity, even if a few got
+        // past the gate.
+        let ready = poll_fn(|cx| Write::<String>::poll_ready(Pin::new(&mut child), cx));
+        assert!(ready.now_or_never().is_none());
+
+        gate.open();
+        assert_eq!(receive::<String, _>(&mut peer).await.unwrap(), text);
+        send(&mut child, text.clone()).await.unwrap();
+        assert_eq!(receive::<String, _>(&mut peer).await.unwrap(), text);
+    });
+}
+
+#[test]
+fn fragments_fit_the_maximum_frame_size_of_the_peer() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect_with(&pool, |builder| builder.max_frame_size(64));
+    let text = "fragmented ".repeat(100);
+
+    block_on(async {
+        let (mut child, mut peer) = open(&mut a, &mut b).await;
+
+        send(&mut child, text.clone()).await.unwrap();
+        assert_eq!(receive::<String, _>(&mut peer).await.unwrap(), text);
+    });
+}
+
+#[test]
+#[should_panic(expected = "window")]
+fn empty_windows_are_rejected_by_the_builder() {
+    let pool = ThreadPool::new().unwrap();
+
+    TransportBuilder::new(pool).limits(BufferLimits {
+        window: 0,
+        ..BufferLimits::default()
+    });
+}
+
+#[test]
+#[should_panic(expected = "bytes")]
+fn frames_too_small_for_any_item_are_rejected_by_the_builder() {
+    let pool = ThreadPool::new().unwrap();
+
+    TransportBuilder::new(pool).max_frame_size(2);
+}
+
+#[test]
+fn items_exceeding_the_maximum_message_size_are_rejected() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect_with(&pool, |builder| builder.max_message_size(256));
+
+    block_on(async {
+        let (mut child, mut peer) = open(&mut a, &mut b).await;
+
+        // More rejected items than the receive window holds, none of which
+        // may use up credit.
+        for _ in 0..64 {
+            match send(&mut child, "oversized ".repeat(100)).await {
+                Err(SerdeWriteError::Oversized { length, .. }) => assert_eq!(length, 8 + 1000),
+                other => panic!("expected the item to be rejected, got {:?}", other),
+            }
+        }
+
+        send(&mut child, 7u64).await.unwrap();
+        assert_eq!(receive::<u64, _>(&mut peer).await.unwrap(), 7);
+    });
+}
+
+#[test]
+fn frames_for_a_closed_context_are_discarded() {
+    let pool = ThreadPool::new().unwrap();
+    let limits = BufferLimits::default();
+    let (mut a, mut b, _) = connect(&pool);
+
+    block_on(async {
+        let (mut child, peer) = open(&mut a, &mut b).await;
+        drop(peer);
+
+        // Writing more than a window's worth only completes once the peer has
+        // released the credit of the context it closed.
+        for item in 0..2 * limits.window as u64 {
+            send(&mut child, item).await.unwrap();
+        }
+
+        send(&mut a, 0u64).await.unwrap();
+        receive::<u64, _>(&mut b).await.unwrap();
+    });
+
+    assert!(b.discarded_frames() >= limits.window as u64);
+}
+
+#[test]
+fn deserialization_errors_leave_the_context_usable() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect(&pool);
+
+    block_on(async {
+        let (mut child, mut peer) = open(&mut a, &mut b).await;
+
+        send(&mut child, 0u8).await.unwrap();
+
+        match receive::<u64, _>(&mut peer).await {
+            Err(SerdeReadError::Serde { length, .. }) => assert_eq!(length, 1),
+            other => panic!("expected a serde error, got {:?}", other.map(|_| ())),
+        }
+
+        send(&mut child, 1u64).await.unwrap();
+        assert_eq!(receive::<u64, _>(&mut peer).await.unwrap(), 1);
+    });
+}
+
+#[test]
+fn borrowed_items_are_deserialized_from_their_frame() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect(&pool);
+
+    block_on(async {
+        let (mut child, mut peer) = open(&mut a, &mut b).await;
+
+        send(&mut child, ("text".to_owned(), vec![1u8, 2, 3]))
+            .await
+            .unwrap();
+        send(&mut child, 7u64).await.unwrap();
+
+        let item = receive::<Borrowed, _>(&mut peer).await.unwrap();
+        let (text, bytes): (&str, &[u8]) = item.deserialize().unwrap();
+        assert_eq!((text, bytes), ("text", &[1, 2, 3][..]));
+
+        let item: Borrowed<Bincode> = receive(&mut peer).await.unwrap();
+        assert!(item.deserialize::<&str>().is_err());
+        assert_eq!(item.deserialize::<u64>().unwrap(), 7);
+    });
+}
+
+#[test]
+fn ends_may_use_different_windows() {
+    let pool = ThreadPool::new().unwrap();
+    let window = |window| BufferLimits {
+        window,
+        ..BufferLimits::default()
+    };
+    let (mut a, mut b, _) = connect_ends(
+        &pool,
+        TransportBuilder::new(pool.clone()).limits(window(64)),
+        TransportBuilder::new(pool.clone()).limits(window(4)),
+    );
+
+    block_on(async {
+        // The end with the larger window writes no more than the other end
+        // allows before it reads.
+        let writer = pool
+            .spawn_with_handle(async move {
+                for item in 0..32u64 {
+                    send(&mut a, item).await.unwrap();
+                }
+            })
+            .unwrap();
+
+        for item in 0..32u64 {
+            assert_eq!(receive::<u64, _>(&mut b).await.unwrap(), item);
+        }
+
+        writer.await;
+    });
+}
+
+#[test]
+fn unjoined_contexts_are_bounded() {
+    let pool = ThreadPool::new().unwrap();
+    let limits = BufferLimits {
+        context_frames: 4,
+        ..BufferLimits::default()
+    };
+    let (mut a, mut b, _) = connect_with(&pool, |builder| builder.limits(limits.clone()));
+
+    block_on(async {
+        let (mut child, _) = run(a.fork_owned(), &mut a).await.ok().unwrap();
+
+        for item in 0..8u64 {
+            send(&mut child, item).await.unwrap();
+        }
+
+        match receive::<u64, _>(&mut b).await {
+            Err(SerdeReadError::BufferExceeded) => {}
+            other => panic!("expected the buffer to overflow, got {:?}", other),
+        }
+    });
+}
+
+#[test]
+fn expired_buffers_credit_their_frames_back() {
+    let pool = ThreadPool::new().unwrap();
+    // Every buffer expires as soon as the next frame arrives.
+    let limits = BufferLimits {
+        window: 4,
+        timeout: Duration::ZERO,
+        ..BufferLimits::default()
+    };
+    let (mut a, mut b, _) = connect_with(&pool, |builder| builder.limits(limits.clone()));
+
+    block_on(async {
+        let (mut child, id) = run(a.fork_owned(), &mut a).await.ok().unwrap();
+
+        // Twice the window, which only fits if expired frames are credited.
+        for item in 0..8u64 {
+            send(&mut child, item).await.unwrap();
+        }
+        send(&mut a, id).await.unwrap();
+
+        let id = receive::<u64, _>(&mut b).await.unwrap();
+        let mut peer = run(b.join_owned(id), &mut b).await.ok().unwrap();
+
+        for item in 8..24u64 {
+            send(&mut child, item).await.unwrap();
+            assert_eq!(receive::<u64, _>(&mut peer).await.unwrap(), item);
+        }
+    });
+}
+
+#[test]
+fn buffers_expire_while_the_connection_is_idle() {
+    let pool = ThreadPool::new().unwrap();
+    let (timer, mut delays) = clock();
+    let limits = BufferLimits {
+        window: 1,
+        timeout: Duration::ZERO,
+        ..BufferLimits::default()
+    };
+    let (mut a, b, _) = connect_with(&pool, |builder| {
+        builder.limits(limits.clone()).timer(timer.clone())
+    });
+
+    block_on(async {
+        let (mut child, _) = run(a.fork_owned(), &mut a).await.ok().unwrap();
+        send(&mut child, 0u64).await.unwrap();
+
+        // Nothing else arrives, so only the timer can expire the buffer.
+        let (_, fire) = delays.next().await.unwrap();
+        fire.send(()).unwrap();
+
+        // The window of the child is only open again once its frame was
+        // dropped and credited back.
+        send(&mut child, 1u64).await.unwrap();
+        assert_eq!(b.discarded_frames(), 1);
+    });
+}
+
+#[test]
+fn shutdown_closes_every_context() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect(&pool);
+
+    block_on(async {
+        let (mut child, mut peer) = open(&mut a, &mut b).await;
+
+        a.shutdown_handle().shutdown().await;
+
+        assert!(send(&mut child, 0u64).await.is_err());
+
+        match receive::<u64, _>(&mut peer).await {
+            Err(SerdeReadError::Terminated) => {}
+            other => panic!("expected the context to terminate, got {:?}", other),
+        }
+        match receive::<u64, _>(&mut b).await {
+            Err(SerdeReadError::Terminated) => {}
+            other => panic!("expected the context to terminate, got {:?}", other),
+        }
+    });
+}
+
+#[test]
+fn dropping_every_context_ends_the_connection() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect(&pool);
+
+    block_on(async {
+        let (child, mut peer) = open(&mut a, &mut b).await;
+
+        drop(child);
+        drop(a);
+
+        match receive::<u64, _>(&mut peer).await {
+            Err(SerdeReadError::Terminated) => {}
+            other => panic!("expected the context to terminate, got {:?}", other),
+        }
+        match receive::<u64, _>(&mut b).await {
+            Err(SerdeReadError::Terminated) => {}
+            other => panic!("expected the context to terminate, got {:?}", other),
+        }
+    });
+}
+
+#[test]
+fn disconnects_fail_pending_reads_and_writes() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, faults) = connect(&pool);
+
+    block_on(async {
+        let (mut child, mut peer) = open(&mut a, &mut b).await;
+
+        let pending = pool
+            .spawn_with_handle(async move { receive::<u64, _>(&mut peer).await })
+            .unwrap();
+
+        faults.disconnect();
+
+        match pending.await {
+            Err(SerdeReadError::Stream(e)) => assert_eq!(*e, Disconnected),
+            other => panic!("expected the stream to fail, got {:?}", other),
+        }
+        match receive::<u64, _>(&mut a).await {
+            Err(SerdeReadError::Stream(e)) => assert_eq!(*e, Disconnected),
+            other => panic!("expected the stream to fail, got {:?}", other),
+        }
+
+        let mut failed = false;
+        for item in 0..64u64 {
+            if send(&mut child, item).await.is_err() {
+                failed = true;
+                break;
+            }
+        }
+        assert!(failed);
+    });
+}
+
+#[test]
+fn shutdown_completes_once_the_sink_has_failed() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, gate) = connect_gated(&pool, |builder| builder);
+
+    block_on(async {
+        let (mut child, _peer, _) = open_gated(&mut a, &mut b).await;
+
+        // Use up the receive window, so that the next write waits for credit
+        // until the sink is found to have failed.
+        for item in 0..32u64 {
+            queue(&mut child, item).await.unwrap();
+        }
+        let blocked = pool
+            .spawn_with_handle(async move {
+                let result = send(&mut child, 32u64).await;
+                (child, result)
+            })
+            .unwrap();
+
+        gate.cut();
+
+        for item in 0..64u64 {
+            if send(&mut a, item).await.is_err() {
+                break;
+            }
+        }
+
+        let (_child, result) = blocked.await;
+        assert!(result.is_err());
+
+        a.shutdown_handle().shutdown().await;
+    });
+}
+
+#[test]
+fn mismatched_fingerprints_are_rejected() {
+    let pool = ThreadPool::new().unwrap();
+    let ((a_stream, a_sink), (b_stream, b_sink)) = memory::pair();
+    let (sender, _receiver) = oneshot::channel::<Connection>();
+
+    let unravel = TransportBuilder::new(pool.clone())
+        .fingerprint(Fingerprint::from_hash(1))
+        .unravel(a_stream, a_sink, Root(Box::new(sender)));
+    let coalesce = TransportBuilder::new(pool.clone())
+        .fingerprint(Fingerprint::from_hash(2))
+        .coalesce::<_, _, Root>(b_stream, b_sink);
+
+    let (unravelled, coalesced) = block_on(join(unravel, coalesce));
+
+    match unravelled {
+        Err(WithSpawnError::Incompatible(Incompatibility::Fingerprint(fingerprint))) => {
+            assert_eq!(fingerprint, Fingerprint::from_hash(2))
+        }
+        other => panic!("expected a fingerprint mismatch, got {:?}", other.err()),
+    }
+    match coalesced {
+        Err(WithSpawnError::Incompatible(Incompatibility::Fingerprint(fingerprint))) => {
+            assert_eq!(fingerprint, Fingerprint::from_hash(1))
+        }
+        other => panic!("expected a fingerprint mismatch, got {:?}", other.err()),
+    }
+}
+
+#[test]
+fn disconnects_before_the_handshake_fail_both_ends() {
+    let pool = ThreadPool::new().unwrap();
+    let ((a_stream, a_sink), (b_stream, b_sink), faults) = memory::faulty_pair();
+    let (sender, _receiver) = oneshot::channel::<Connection>();
+
+    faults.disconnect();
+
+    let unravel = Unravel::new(a_stream, a_sink, pool.clone(), Root(Box::new(sender)));
+    let coalesce: Coalesce<_, _, _, Root> = Coalesce::new(b_stream, b_sink, pool.clone());
+
+    let (unravelled, coalesced) = block_on(join(unravel, coalesce));
+
+    assert!(matches!(
+        unravelled,
+        Err(WithSpawnError::Connection(SerdeReadError::Stream(_)))
+    ));
+    assert!(matches!(
+        coalesced,
+        Err(WithSpawnError::Connection(SerdeReadError::Stream(_)))
+    ));
+}
+
+#[test]
+fn peers_hanging_up_before_the_handshake_are_reported() {
+    let pool = ThreadPool::new().unwrap();
+    let (_, (b_stream, b_sink)) = memory::pair();
+
+    let coalesce: Coalesce<_, _, _, Root> = Coalesce::new(b_stream, b_sink, pool.clone());
+
+    match block_on(coalesce) {
+        Err(WithSpawnError::Connection(SerdeReadError::Terminated)) => {}
+        other => panic!("expected the connection to end, got {:?}", other.err()),
+    }
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn encrypted_frames_round_trip() {
+    use protocol_mve_transport::PreSharedKey;
+
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, gate) = connect_gated(&pool, |builder| {
+        builder.pre_shared_key(PreSharedKey::new([7; 32]))
+    });
+    let text = "attack at dawn".to_owned();
+
+    block_on(async {
+        let (mut child, mut peer, _) = open_gated(&mut a, &mut b).await;
+
+        send(&mut child, text.clone()).await.unwrap();
+        assert_eq!(receive::<String, _>(&mut peer).await.unwrap(), text);
+
+        send(&mut peer, 42u64).await.unwrap();
+        assert_eq!(receive::<u64, _>(&mut child).await.unwrap(), 42);
+    });
+
+    let log = gate.log.lock().unwrap();
+
+    assert!(log.len() > 1);
+    assert!(!log.iter().any(|frame| frame
+        .windows(text.len())
+        .any(|window| window == text.as_bytes())));
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn frames_sealed_with_another_key_fail_authentication() {
+    use protocol_mve_transport::PreSharedKey;
+
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, _) = connect_ends(
+        &pool,
+        TransportBuilder::new(pool.clone()).pre_shared_key(PreSharedKey::new([1; 32])),
+        TransportBuilder::new(pool.clone()).pre_shared_key(PreSharedKey::new([2; 32])),
+    );
+
+    block_on(async {
+        send(&mut a, 1u64).await.unwrap();
+        assert!(matches!(
+            receive::<u64, _>(&mut b).await,
+            Err(SerdeReadError::Authentication)
+        ));
+    });
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn tampered_handshakes_fail_authentication() {
+    use protocol_mve_transport::PreSharedKey;
+
+    let pool = ThreadPool::new().unwrap();
+    let ((a_stream, a_sink), (b_stream, b_sink)) = memory::pair();
+    let (sender, receiver) = oneshot::channel::<Connection>();
+    let key = PreSharedKey::new([1; 32]);
+
+    // Raises the largest item the unravelling end announces, the low byte of
+    // which sits ahead of the nonce prefix and checksum flag, leaving a
+    // handshake the coalescing end still agrees to.
+    let mut handshake = true;
+    let a_sink = a_sink.with(move |mut frame: Vec<u8>| {
+        if handshake {
+            handshake = false;
+            let offset = frame.len() - 26;
+            frame[offset] ^= 1;
+        }
+        ready(Ok::<_, Disconnected>(frame))
+    });
+
+    let unravel = TransportBuilder::new(pool.clone())
+        .pre_shared_key(key.clone())
+        .unravel(a_stream, a_sink, Root(Box::new(sender)));
+    pool.spawn(async move {
+        let _ = unravel.await;
+    })
+    .unwrap();
+
+    let coalesce = TransportBuilder::new(pool.clone())
+        .pre_shared_key(key)
+        .coalesce::<_, _, Root>(b_stream, b_sink);
+    let mut b: Connection = *block_on(coalesce).ok().unwrap().0.downcast().unwrap();
+    let mut a = block_on(receiver).unwrap();
+
+    block_on(async {
+        send(&mut a, 1u64).await.unwrap();
+        assert!(matches!(
+            receive::<u64, _>(&mut b).await,
+            Err(SerdeReadError::Authentication)
+        ));
+    });
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn encryption_at_one_end_is_rejected() {
+    use protocol_mve_transport::PreSharedKey;
+
+    let pool = ThreadPool::new().unwrap();
+    let ((a_stream, a_sink), (b_stream, b_sink)) = memory::pair();
+    let (sender, _receiver) = oneshot::channel::<Connection>();
+
+    let unravel = TransportBuilder::new(pool.clone())
+        .pre_shared_key(PreSharedKey::new([1; 32]))
+        .unravel(a_stream, a_sink, Root(Box::new(sender)));
+    let coalesce: Coalesce<_, _, _, Root> = Coalesce::new(b_stream, b_sink, pool.clone());
+
+    let (unravelled, coalesced) = block_on(join(unravel, coalesce));
+
+    assert!(matches!(
+        unravelled,
+        Err(WithSpawnError::Incompatible(Incompatibility::Encryption))
+    ));
+    assert!(matches!(
+        coalesced,
+        Err(WithSpawnError::Incompatible(Incompatibility::Encryption))
+    ));
+}
+
+#[test]
+fn checksums_are_used_if_either_end_asks() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, faults) = connect_ends(
+        &pool,
+        TransportBuilder::new(pool.clone()).checksums(true),
+        TransportBuilder::new(pool.clone()),
+    );
+
+    block_on(async {
+        send(&mut a, 1u64).await.unwrap();
+        assert_eq!(receive::<u64, _>(&mut b).await.unwrap(), 1);
+        send(&mut b, 2u64).await.unwrap();
+        assert_eq!(receive::<u64, _>(&mut a).await.unwrap(), 2);
+
+        // The end that didn't ask for checksums verifies them all the same.
+        faults.corrupt();
+        send(&mut a, 3u64).await.unwrap();
+        assert!(matches!(
+            receive::<u64, _>(&mut b).await,
+            Err(SerdeReadError::Corrupt { .. })
+        ));
+    });
+}
+
+#[test]
+fn corrupted_frames_are_caught_by_checksums() {
+    let pool = ThreadPool::new().unwrap();
+    let (mut a, mut b, faults) = connect_with(&pool, |builder| builder.checksums(true));
+
+    faults.corrupt();
+
+    block_on(async {
+        send(&mut a, 1u64).await.unwrap();
+        assert!(matches!(
+            receive::<u64, _>(&mut b).await,
+            Err(SerdeReadError::Corrupt { handle: Some(_) })
+        ));
+    });
+}
+
+#[test]
+fn keepalive_pings_measure_the_round_trip_time() {
+    let pool = ThreadPool::new().unwrap();
+    let (timer, mut delays) = clock();
+    let interval = Duration::from_secs(1);
+    let (mut a, mut b, _) = connect_with(&pool, |builder| {
+        builder.keepalive(timer.clone(), interval, Duration::from_secs(5))
+    });
+
+    block_on(async {
+        // Once both ends have answered a ping, they wait another interval
+        // before the next, so that each round fires both pings.
+        for _ in 0..2 {
+            let mut waiting = vec![];
+
+            while waiting.len() < 2 {
+                let (duration, fire) = delays.next().await.unwrap();
+                if duration == interval {
+                    waiting.push(fire);
+                }
+            }
+
+            for fire in waiting {
+                fire.send(()).unwrap();
+            }
+        }
+
+        assert!(a.round_trip_time().is_some());
+        assert!(b.round_trip_time().is_some());
+
+        // A peer answering its pings keeps the connection alive.
+        send(&mut a, 1u64).await.unwrap();
+        assert_eq!(receive::<u64, _>(&mut b).await.unwrap(), 1);
+    });
+}
+
+#[test]
+fn unresponsive_peers_time_out() {
+    let pool = ThreadPool::new().unwrap();
+    let (timer, delays) = clock();
+    let (mut a, mut b, faults) = connect_with(&pool, |builder| {
+        builder.keepalive(
+            timer.clone(),
+            Duration::from_secs(1),
+            Duration::from_secs(5),
+        )
+    });
+
+    block_on(async {
+        let (mut child, _peer) = open(&mut a, &mut b).await;
+
+        faults.hold();
+
+        // Pings are held back, so the pongs never arrive before the timeout.
+        pool.spawn(delays.for_each(|(_, fire)| {
+            let _ = fire.send(());
+            ready(())
+        }))
+        .unwrap();
+
+        assert!(matches!(
+            receive::<u64, _>(&mut a).await,
+            Err(SerdeReadError::Timeout)
+        ));
+        assert!(matches!(
+            receive::<u64, _>(&mut child).await,
+            Err(SerdeReadError::Timeout)
+        ));
+    });
+}
+
+/// Hands out the bytes of `data` at most `step` at a time, returning
+/// `Pending` before every read.
+struct Trickle {
+    data: Vec<u8>,
+    position: usize,
+    step: usize,
+    ready: bool,
+}
+
+impl Trickle {
+    fn new(data: Vec<u8>, step: usize) -> Self {
+        Trickle {
+            data,
+            position: 0,
+            step,
+            ready: false,
+        }
+    }
+}
+
+impl AsyncRead for Trickle {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if !self.ready {
+            self.ready = true;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+
+        self.ready = false;
+
+        let start = self.position;
+        let end = (start + self.step.min(buf.len())).min(self.data.len());
+
+        buf[..end - start].copy_from_slice(&self.data[start..end]);
+        self.position = end;
+
+        Poll::Ready(Ok(end - start))
+    }
+}
+
+/// Writes the given frames with a `FramedWrite`, returning the bytes written.
+fn write_frames(frames: &[&[u8]]) -> Vec<u8> {
+    let mut data = vec![];
+
+    block_on(async {
+        let mut writer = FramedWrite::new(&mut data);
+
+        for frame in frames {
+            writer.feed(frame.to_vec()).await.unwrap();
+        }
+        writer.close().await.unwrap();
+    });
+
+    data
+}
+
+/// Reads every frame of `data` with a `FramedRead`, a few bytes at a time.
+fn read_frames(data: Vec<u8>, max_frame_size: usize) -> Vec<io::Result<Vec<u8>>> {
+    block_on(
+        FramedRead::new(Trickle::new(data, 3), max_frame_size)
+            .map_ok(|frame| frame.to_vec())
+            .collect(),
+    )
+}
+
+fn framing_error(error: &io::Error) -> Option<&FramingError> {
+    error.get_ref()?.downcast_ref()
+}
+
+#[test]
+fn framed_streams_round_trip() {
+    let large = vec![7; 100_000];
+    let frames: [&[u8]; 4] = [b"first", b"", &large, b"last"];
+
+    let data = write_frames(&frames);
+    assert_eq!(&data[..4], &[0, 0, 0, 5]);
+
+    let read = read_frames(data, large.len())
+        .into_iter()
+        .collect::<io::Result<Vec<_>>>()
+        .unwrap();
+
+    assert_eq!(
+        read,
+        frames
+            .iter()
+            .map(|frame| frame.to_vec())
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn oversized_length_prefixes_are_rejected() {
+    let mut data = write_frames(&[b"fine"]);
+    data.extend_from_slice(&u32::MAX.to_be_bytes());
+
+    let read = read_frames(data, 1024);
+
+    assert_eq!(read.len(), 2);
+    assert_eq!(read[0].as_ref().unwrap(), b"fine");
+
+    let error = read[1].as_ref().unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(
+        framing_error(error),
+        Some(&FramingError::Oversized(u32::MAX as usize))
+    );
+}
+
+#[test]
+fn truncated_streams_are_reported() {
+    assert!(read_frames(vec![], 1024).is_empty());
+
+    let mut data = write_frames(&[b"fine", b"truncated"]);
+    data.truncate(data.len() - 1);
+
+    let read = read_frames(data, 1024);
+
+    assert_eq!(read.len(), 2);
+    assert_eq!(read[0].as_ref().unwrap(), b"fine");
+    assert_eq!(
+        framing_error(read[1].as_ref().unwrap_err()),
+        Some(&FramingError::Truncated)
+    );
+}
+
+type Pipe = IntoAsyncRead<mpsc::UnboundedReceiver<io::Result<Vec<u8>>>>;
+
+type IoConnection = Transport<ThreadPool, io::Error, io::Error, Root>;
+
+/// The writing half of an in-memory byte stream.
+struct PipeWriter(mpsc::UnboundedSender<io::Result<Vec<u8>>>);
+
+impl AsyncWrite for PipeWriter {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(
+            self.0
+                .unbounded_send(Ok(buf.to_vec()))
+                .map(|_| buf.len())
+                .map_err(|_| io::ErrorKind::BrokenPipe.into()),
+        )
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        self.0.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn pipe() -> (Pipe, PipeWriter) {
+    let (sender, receiver) = mpsc::unbounded();
+    (receiver.into_async_read(), PipeWriter(sender))
+}
+
+#[test]
+fn connections_run_over_byte_streams() {
+    let pool = ThreadPool::new().unwrap();
+    let (a_reader, b_writer) = pipe();
+    let (b_reader, a_writer) = pipe();
+    let (sender, receiver) = oneshot::channel::<IoConnection>();
+
+    let unravel = Unravel::from_io(a_reader, a_writer, pool.clone(), Root(Box::new(sender)));
+    pool.spawn(async move {
+        let _ = unravel.await;
+    })
+    .unwrap();
+
+    let coalesce: Coalesce<_, _, _, Root> = Coalesce::from_io(b_reader, b_writer, pool.clone());
+    let mut b: IoConnection = *block_on(coalesce).ok().unwrap().0.downcast().unwrap();
+    let mut a = block_on(receiver).unwrap();
+    let text = "framed ".repeat(10_000);
+
+    block_on(async {
+        send(&mut a, text.clone()).await.unwrap();
+        assert_eq!(receive::<String, _>(&mut b).await.unwrap(), text);
+        send(&mut b, 7u64).await.unwrap();
+        assert_eq!(receive::<u64, _>(&mut a).await.unwrap(), 7);
+    });
+}
+
+#[test]
+fn framing_errors_end_the_connection() {
+    let pool = ThreadPool::new().unwrap();
+    let (reader, writer) = pipe();
+    let (_, b_writer) = pipe();
+
+    writer.0.unbounded_send(Ok(vec![0xff; 4])).unwrap();
+
+    let coalesce: Coalesce<_, _, _, Root> = Coalesce::from_io(reader, b_writer, pool);
+
+    match block_on(coalesce) {
+        Err(WithSpawnError::Connection(SerdeReadError::Framing(FramingError::Oversized(
+            length,
+        )))) => assert_eq!(length, u32::MAX as usize),
+        other => panic!("expected a framing error, got {:?}", other.err()),
+    }
+}